@@ -1,60 +1,66 @@
+use crate::amount::{Amount, AmountError};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Account {
     pub accounts: Vec<String>,
-    pub balances: HashMap<String, f64>,
+    pub balances: HashMap<String, Amount>,
+    pub nonces: HashMap<String, u64>,
 }
 
 impl Account {
     pub fn new() -> Self {
         Self {
-            accounts: vec![
-                String::from("230681c76f00b412ccf7757a8449c448a04acd735e497a7612b66d8bfcb8e576"),
-                String::from("5aede624154386ca358af195e13a46981b917ee8279f30a67d7a211a3d3e7243"),
-            ],
-            balances: HashMap::from([
-                (
-                    String::from(
-                        "230681c76f00b412ccf7757a8449c448a04acd735e497a7612b66d8bfcb8e576",
-                    ),
-                    500.00,
-                ),
-                (
-                    String::from(
-                        "5aede624154386ca358af195e13a46981b917ee8279f30a67d7a211a3d3e7243",
-                    ),
-                    500.00,
-                ),
-            ]),
+            accounts: vec![],
+            balances: HashMap::new(),
+            nonces: HashMap::new(),
         }
     }
 
     pub fn initialize(&mut self, address: &String) {
         if !self.balances.contains_key(address) {
-            self.balances.insert(address.to_string(), 0.00);
+            self.balances.insert(address.to_string(), Amount::ZERO);
             self.accounts.push(address.to_string());
         }
     }
 
-    pub fn transfer(&mut self, from: &String, to: &String, amount: &f64) {
+    pub fn transfer(
+        &mut self,
+        from: &String,
+        to: &String,
+        amount: &Amount,
+    ) -> Result<(), AmountError> {
         self.initialize(from);
         self.initialize(to);
-        self.increment(to, amount);
-        self.decrement(from, amount);
+        self.decrement(from, amount)?;
+        self.increment(to, amount)
     }
 
-    pub fn increment(&mut self, to: &String, amount: &f64) {
-        (*self.balances.get_mut(to).unwrap()) += amount;
+    pub fn increment(&mut self, to: &String, amount: &Amount) -> Result<(), AmountError> {
+        self.initialize(to);
+        let balance = self.balances.get_mut(to).unwrap();
+        *balance = balance.checked_add(*amount).ok_or(AmountError::Overflow)?;
+        Ok(())
+    }
+
+    pub fn decrement(&mut self, from: &String, amount: &Amount) -> Result<(), AmountError> {
+        self.initialize(from);
+        let balance = self.balances.get_mut(from).unwrap();
+        *balance = balance.checked_sub(*amount).ok_or(AmountError::Underflow)?;
+        Ok(())
+    }
+
+    /// Nonce the next transaction from `address` has to carry.
+    pub fn get_nonce(&self, address: &String) -> u64 {
+        *self.nonces.get(address).unwrap_or(&0)
     }
 
-    pub fn decrement(&mut self, from: &String, amount: &f64) {
-        (*self.balances.get_mut(from).unwrap()) -= amount;
+    pub fn increment_nonce(&mut self, address: &String) {
+        *self.nonces.entry(address.to_string()).or_insert(0) += 1;
     }
 
-    pub fn get_balance(&mut self, address: &String) -> &f64 {
-        self.initialize(address);
-        self.balances.get(address).unwrap()
+    pub fn get_balance(&self, address: &String) -> Amount {
+        self.balances.get(address).copied().unwrap_or_default()
     }
 }
@@ -0,0 +1,181 @@
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+pub const DECIMALS: u32 = 8;
+pub const UNIT: u64 = 10u64.pow(DECIMALS);
+
+/// Token amount in integer base units, `UNIT` base units make one coin.
+/// Serialized as a decimal string such as `"12.5"` so no precision is lost
+/// in JSON.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Amount(u64);
+
+#[derive(Debug, PartialEq)]
+pub enum AmountError {
+    Overflow,
+    Underflow,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ParseAmountError {
+    Empty,
+    InvalidDigit,
+    TooManyDecimals,
+    Overflow,
+}
+
+impl fmt::Display for ParseAmountError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseAmountError::Empty => write!(f, "empty amount"),
+            ParseAmountError::InvalidDigit => write!(f, "invalid digit in amount"),
+            ParseAmountError::TooManyDecimals => {
+                write!(f, "amount has more than {} decimals", DECIMALS)
+            }
+            ParseAmountError::Overflow => write!(f, "amount is too large"),
+        }
+    }
+}
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+
+    pub const fn from_coins(coins: u64) -> Self {
+        Amount(coins * UNIT)
+    }
+
+    pub const fn from_units(units: u64) -> Self {
+        Amount(units)
+    }
+
+    pub fn units(&self) -> u64 {
+        self.0
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Amount)
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let whole = self.0 / UNIT;
+        let frac = self.0 % UNIT;
+        if frac == 0 {
+            return write!(f, "{}", whole);
+        }
+        let frac = format!("{:0width$}", frac, width = DECIMALS as usize);
+        write!(f, "{}.{}", whole, frac.trim_end_matches('0'))
+    }
+}
+
+impl FromStr for Amount {
+    type Err = ParseAmountError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (whole, frac) = match s.split_once('.') {
+            Some((whole, frac)) => (whole, frac),
+            None => (s, ""),
+        };
+        if whole.is_empty() && frac.is_empty() {
+            return Err(ParseAmountError::Empty);
+        }
+        if !whole
+            .chars()
+            .chain(frac.chars())
+            .all(|c| c.is_ascii_digit())
+        {
+            return Err(ParseAmountError::InvalidDigit);
+        }
+        if frac.len() > DECIMALS as usize {
+            return Err(ParseAmountError::TooManyDecimals);
+        }
+
+        let whole = match whole {
+            "" => 0,
+            _ => whole
+                .parse::<u64>()
+                .map_err(|_| ParseAmountError::Overflow)?,
+        };
+        let frac = match frac {
+            "" => 0,
+            _ => {
+                frac.parse::<u64>()
+                    .map_err(|_| ParseAmountError::Overflow)?
+                    * 10u64.pow(DECIMALS - frac.len() as u32)
+            }
+        };
+
+        whole
+            .checked_mul(UNIT)
+            .and_then(|units| units.checked_add(frac))
+            .map(Amount)
+            .ok_or(ParseAmountError::Overflow)
+    }
+}
+
+impl Serialize for Amount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse::<Amount>().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_strings() {
+        for (s, units) in [
+            ("0", 0),
+            ("1", UNIT),
+            ("12.5", 12 * UNIT + UNIT / 2),
+            ("0.00000001", 1),
+            ("184467440737.09551615", u64::MAX),
+        ] {
+            let amount: Amount = s.parse().unwrap();
+            assert_eq!(amount.units(), units);
+            assert_eq!(amount.to_string(), s);
+        }
+        assert_eq!("1.50".parse::<Amount>().unwrap().to_string(), "1.5");
+        assert_eq!(".5".parse::<Amount>(), Ok(Amount::from_units(UNIT / 2)));
+    }
+
+    #[test]
+    fn rejects_malformed_strings() {
+        assert_eq!("".parse::<Amount>(), Err(ParseAmountError::Empty));
+        assert_eq!(".".parse::<Amount>(), Err(ParseAmountError::Empty));
+        assert_eq!("-1".parse::<Amount>(), Err(ParseAmountError::InvalidDigit));
+        assert_eq!(
+            "1.2.3".parse::<Amount>(),
+            Err(ParseAmountError::InvalidDigit)
+        );
+        assert_eq!(
+            "0.000000001".parse::<Amount>(),
+            Err(ParseAmountError::TooManyDecimals)
+        );
+        assert_eq!(
+            "184467440737.09551616".parse::<Amount>(),
+            Err(ParseAmountError::Overflow)
+        );
+    }
+
+    #[test]
+    fn serializes_as_a_decimal_string() {
+        let amount = Amount::from_units(12 * UNIT + UNIT / 2);
+        assert_eq!(serde_json::to_string(&amount).unwrap(), "\"12.5\"");
+        assert_eq!(serde_json::from_str::<Amount>("\"12.5\"").unwrap(), amount);
+    }
+}
@@ -1,120 +1,295 @@
+use crate::encoding::Encoder;
+use crate::genesis::Genesis;
+use crate::merkle::{self, MerkleProof};
+use crate::state::{State, TransactionError};
 use crate::util::Util;
 use crate::wallet::Wallet;
 use crate::{block, transaction::Transaction};
 use log::info;
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use uuid::Uuid;
 
+/// Everything the block hash and validator signature commit to. Transactions
+/// are only referenced through `tx_root`, so a header is enough to check an
+/// inclusion proof. `state_root` is `State::root` after executing the block.
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Block {
+pub struct BlockHeader {
+    pub chain_id: String,
     pub id: usize,
-    pub hash: String,
     pub previous_hash: String,
     pub timestamp: i64,
-    pub txn: Vec<Transaction>,
+    pub tx_root: String,
+    pub state_root: String,
     pub validator: String,
-    pub signature: String,
     pub difficulty: u32,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Block {
+    pub header: BlockHeader,
+    pub hash: String,
+    pub signature: String,
+    pub txn: Vec<Transaction>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BlockValidationError {
+    InvalidGenesis,
+    WrongChain(String),
+    UnknownParent(String),
+    ConflictsWithFinalized(usize),
+    WrongPreviousHash {
+        expected: String,
+        found: String,
+    },
+    InvalidHash,
+    InvalidTxRoot,
+    NonSequentialId {
+        expected: usize,
+        found: usize,
+    },
+    InvalidSignature,
+    InvalidDifficulty {
+        expected: u32,
+        found: u32,
+    },
+    NotValidator(String),
+    InvalidStakeProof,
+    TimestampOutOfRange(i64),
+    TooManyTransactions {
+        max: usize,
+        found: usize,
+    },
+    TooLarge {
+        max: usize,
+        found: usize,
+    },
+    InvalidTransaction {
+        index: usize,
+        error: TransactionError,
+    },
+    StateRootMismatch {
+        expected: String,
+        found: String,
+    },
+    Overflow,
+}
+
+impl fmt::Display for BlockValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockValidationError::InvalidGenesis => write!(f, "genesis block does not match"),
+            BlockValidationError::WrongChain(chain_id) => {
+                write!(f, "block is for chain {}", chain_id)
+            }
+            BlockValidationError::UnknownParent(hash) => write!(f, "unknown parent block {}", hash),
+            BlockValidationError::ConflictsWithFinalized(height) => {
+                write!(f, "conflicts with the finalized block at height {}", height)
+            }
+            BlockValidationError::WrongPreviousHash { expected, found } => {
+                write!(f, "wrong previous hash {}, expected {}", found, expected)
+            }
+            BlockValidationError::InvalidHash => write!(f, "hash does not match header"),
+            BlockValidationError::InvalidTxRoot => {
+                write!(f, "transaction root does not match transactions")
+            }
+            BlockValidationError::NonSequentialId { expected, found } => {
+                write!(f, "id {} is not the next id {}", found, expected)
+            }
+            BlockValidationError::InvalidSignature => write!(f, "invalid validator signature"),
+            BlockValidationError::InvalidDifficulty { expected, found } => {
+                write!(
+                    f,
+                    "difficulty {} does not match expected {}",
+                    found, expected
+                )
+            }
+            BlockValidationError::NotValidator(address) => {
+                write!(f, "{} is not an active validator", address)
+            }
+            BlockValidationError::InvalidStakeProof => {
+                write!(f, "validator stake does not satisfy the difficulty")
+            }
+            BlockValidationError::TimestampOutOfRange(timestamp) => {
+                write!(f, "timestamp {} is out of range", timestamp)
+            }
+            BlockValidationError::TooManyTransactions { max, found } => {
+                write!(f, "{} transactions exceed the limit of {}", found, max)
+            }
+            BlockValidationError::TooLarge { max, found } => {
+                write!(
+                    f,
+                    "{} bytes of transactions exceed the limit of {}",
+                    found, max
+                )
+            }
+            BlockValidationError::InvalidTransaction { index, error } => {
+                write!(f, "invalid transaction at index {}: {}", index, error)
+            }
+            BlockValidationError::StateRootMismatch { expected, found } => write!(
+                f,
+                "state root {} does not match execution result {}",
+                found, expected
+            ),
+            BlockValidationError::Overflow => write!(f, "amount overflow"),
+        }
+    }
+}
+
 impl PartialEq for Block {
     fn eq(&self, other: &Self) -> bool {
-        self.id == other.id && self.previous_hash == other.previous_hash
+        self.header.id == other.header.id && self.header.previous_hash == other.header.previous_hash
+    }
+}
+
+impl BlockHeader {
+    pub fn verify_txn_inclusion(&self, txn_hash: &str, proof: &MerkleProof) -> bool {
+        merkle::verify(txn_hash, proof, &self.tx_root)
     }
 }
 
 impl Block {
+    /// Builds and signs the block following `previous`, on the same chain.
     pub fn new(
-        id: usize,
-        previous_hash: String,
+        previous: &Block,
         timestamp: i64,
         txn: Vec<Transaction>,
+        state_root: String,
         difficulty: u32,
         mut validator_wallet: Wallet,
     ) -> Self {
-        let validator = validator_wallet.get_public_key();
-        let hash = block::calculate_hash(
-            &id,
-            &timestamp,
-            &previous_hash,
-            &txn,
-            &validator,
-            &difficulty,
-        );
+        let header = BlockHeader {
+            chain_id: previous.header.chain_id.clone(),
+            id: previous.header.id + 1,
+            previous_hash: previous.hash.clone(),
+            timestamp,
+            tx_root: block::calculate_tx_root(&txn),
+            state_root,
+            validator: validator_wallet.get_public_key(),
+            difficulty,
+        };
+        let hash = block::calculate_hash(&header);
         let signature = validator_wallet.sign(&hash);
         Self {
-            id,
+            header,
             hash,
-            previous_hash,
-            timestamp,
-            txn,
-            validator,
             signature,
-            difficulty,
+            txn,
         }
     }
 
-    pub fn genesis() -> Self {
-        let id = 0;
-        let timestamp = 1650205976;
-        let previous_hash = String::from("genesis");
+    /// The first block of the chain started from `genesis`. Its
+    /// `previous_hash` is the hash of the genesis parameters.
+    pub fn genesis(genesis: &Genesis) -> Self {
         let txn = vec![];
-        let validator = String::from("genesis");
-        let signature = String::from("genesis");
-        let difficulty = 5;
-
-        let hash = block::calculate_hash(
-            &id,
-            &timestamp,
-            &previous_hash,
-            &txn,
-            &validator,
-            &difficulty,
-        );
+        let header = BlockHeader {
+            chain_id: genesis.chain_id.clone(),
+            id: 0,
+            previous_hash: genesis.hash(),
+            timestamp: genesis.timestamp,
+            tx_root: block::calculate_tx_root(&txn),
+            state_root: State::new(genesis).root(),
+            validator: String::from("genesis"),
+            difficulty: genesis.difficulty,
+        };
+        let hash = block::calculate_hash(&header);
 
         Self {
-            id,
+            header,
             hash,
-            previous_hash,
-            timestamp,
+            signature: String::from("genesis"),
             txn,
-            validator,
-            signature,
-            difficulty,
         }
     }
 
+    /// Total size of the block's transactions, see `Transaction::size`.
+    pub fn txn_bytes(&self) -> usize {
+        self.txn.iter().map(|txn| txn.size()).sum()
+    }
+
     pub fn verify_block_signature(block: &Block) -> bool {
         info!("verifying block...");
-        let hash = block::calculate_hash(
-            &block.id,
-            &block.timestamp,
-            &block.previous_hash,
-            &block.txn,
-            &block.validator,
-            &block.difficulty,
-        );
+        let hash = block::calculate_hash(&block.header);
 
-        Util::verify_signature(&block.validator, &hash, &block.signature).is_ok()
+        matches!(
+            Util::verify_signature(&block.header.validator, &hash, &block.signature),
+            Ok(true)
+        )
     }
+
+    pub fn prove_txn(&self, id: &Uuid) -> Option<MerkleProof> {
+        let index = self.txn.iter().position(|txn| txn.id == *id)?;
+        let hashes: Vec<String> = self.txn.iter().map(|txn| txn.hash()).collect();
+        merkle::prove(&hashes, index)
+    }
+}
+
+/// Merkle root over `Transaction::hash` of each transaction, in block order.
+pub fn calculate_tx_root(txn: &[Transaction]) -> String {
+    let hashes: Vec<String> = txn.iter().map(|txn| txn.hash()).collect();
+    merkle::merkle_root(&hashes)
 }
 
-pub fn calculate_hash(
-    id: &usize,
-    timestamp: &i64,
-    previous_hash: &str,
-    txn: &Vec<Transaction>,
-    validator: &String,
-    difficulty: &u32,
-) -> String {
+/// Hash of the canonical encoding of the block header.
+pub fn calculate_hash(header: &BlockHeader) -> String {
     info!("calculating hash...");
-    let hash = serde_json::json!({
-        "id": id,
-        "previous_hash": previous_hash,
-        "transactions": txn,
-        "timestamp": timestamp,
-        "validator": validator,
-        "difficulty": difficulty,
-    });
-
-    Util::hash(&hash.to_string())
+    let bytes = Encoder::new("pos/block/v4")
+        .str(&header.chain_id)
+        .u64(header.id as u64)
+        .str(&header.previous_hash)
+        .i64(header.timestamp)
+        .str(&header.tx_root)
+        .str(&header.state_root)
+        .str(&header.validator)
+        .u32(header.difficulty)
+        .finish();
+
+    Util::hash_bytes(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::tests::golden_txn;
+
+    #[test]
+    fn matches_golden_vectors() {
+        let genesis = Genesis::default();
+        let block = Block::genesis(&genesis);
+        assert_eq!(
+            genesis.hash(),
+            "e7ebd30299e4f9a625c8329414a97c8f37ac9ba3cbe4b938200df88bc696173d"
+        );
+        assert_eq!(block.header.previous_hash, genesis.hash());
+        assert_eq!(
+            block.header.state_root,
+            "36fb92a44f9a1d874f718accbe32d019dd7035c103e015e92971961985a28587"
+        );
+        assert_eq!(
+            block.hash,
+            "aee5164e2c999208dbd3be6f5b118e4d106aadb2376f81805d37d347d4da8375"
+        );
+
+        let header = BlockHeader {
+            chain_id: String::from("pos-devnet"),
+            id: 1,
+            previous_hash: block.hash.clone(),
+            timestamp: 1650206000,
+            tx_root: calculate_tx_root(&[golden_txn()]),
+            state_root: block.header.state_root.clone(),
+            validator: String::from(
+                "8a88e3dd7409f195fd52db2d3cba5d72ca6709bf1d94121bf3748801b40f6f5c",
+            ),
+            difficulty: 5,
+        };
+        assert_eq!(
+            header.tx_root,
+            "efd4ef6b0cfc337d4b8c86293c59a597679363cf23a1a7a27b54869999c12d10"
+        );
+        assert_eq!(
+            calculate_hash(&header),
+            "bb931392c7a520e77f89b2f876b9ede495b4ed7f92744d59f84b58e57b3b5f79"
+        );
+    }
 }
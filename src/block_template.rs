@@ -0,0 +1,131 @@
+use std::collections::HashSet;
+
+use log::warn;
+
+use crate::state::State;
+use crate::transaction::Transaction;
+
+/// The transactions of a block being assembled, executed on a copy of the
+/// state as they are added. The block is full once it reaches
+/// `max_block_txns` or `max_block_bytes` of `ChainConfig`.
+pub struct BlockTemplate {
+    pub state: State,
+    pub txns: Vec<Transaction>,
+    validator: String,
+    bytes: usize,
+    // Senders with a transaction left out, their later ones would fail on
+    // the nonce.
+    skipped: HashSet<String>,
+}
+
+impl BlockTemplate {
+    /// Starts the block at `height` on `state`, crediting the reward to
+    /// `validator`.
+    pub fn new(mut state: State, height: usize, validator: String) -> Self {
+        if let Err(e) = state.begin_block(height, &validator) {
+            warn!("Failed to start block: {:?}", e);
+        }
+        Self {
+            state,
+            txns: vec![],
+            validator,
+            bytes: 0,
+            skipped: HashSet::new(),
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.txns.len() >= self.state.config.max_block_txns
+    }
+
+    /// Adds `pending` in order until the block is full. Transactions that
+    /// don't fit in the remaining space or fail to execute are left out,
+    /// together with the rest of their sender's.
+    pub fn fill<'a>(&mut self, pending: impl IntoIterator<Item = &'a Transaction>) {
+        for txn in pending {
+            if self.is_full() {
+                break;
+            }
+            self.add(txn);
+        }
+    }
+
+    fn add(&mut self, txn: &Transaction) {
+        let from = &txn.txn_input.from;
+        if self.skipped.contains(from) {
+            return;
+        }
+
+        let size = txn.size();
+        if self.bytes + size > self.state.config.max_block_bytes {
+            self.skipped.insert(from.clone());
+            return;
+        }
+        if let Err(e) = self.state.apply_txn(txn, &self.validator) {
+            warn!("Leaving out transaction {}: {}", txn.id, e);
+            self.skipped.insert(from.clone());
+            return;
+        }
+        self.bytes += size;
+        self.txns.push(txn.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::genesis::Genesis;
+    use crate::transaction::TransactionType;
+    use crate::wallet::Wallet;
+
+    const KEY: &str = "27a23bf39574e86464f4e638241b3ef3dd223d9a30bd97810ff29c992e747e5a230681c76f00b412ccf7757a8449c448a04acd735e497a7612b66d8bfcb8e576";
+    const OTHER_KEY: &str = "5ae5066dd048ffb8f8628c44324e63c7b8782a026009a85a96935acb4921abbc5aede624154386ca358af195e13a46981b917ee8279f30a67d7a211a3d3e7243";
+
+    fn txns(key: &str, to: &str, count: u64) -> Vec<Transaction> {
+        let mut wallet = Wallet::get_wallet(key.to_string());
+        (0..count)
+            .map(|nonce| {
+                Transaction::new(
+                    &mut wallet,
+                    "pos-devnet",
+                    to.to_string(),
+                    "1".parse().unwrap(),
+                    "1".parse().unwrap(),
+                    TransactionType::TRANSACTION,
+                    nonce,
+                )
+            })
+            .collect()
+    }
+
+    fn template(genesis: &Genesis) -> BlockTemplate {
+        BlockTemplate::new(State::new(genesis), 1, genesis.validators[0].clone())
+    }
+
+    #[test]
+    fn stops_at_the_transaction_limit() {
+        let mut genesis = Genesis::default();
+        genesis.config.max_block_txns = 2;
+        let pending = txns(KEY, &genesis.validators[1], 3);
+
+        let mut template = template(&genesis);
+        template.fill(&pending);
+        assert_eq!(template.txns, pending[..2].to_vec());
+        assert!(template.is_full());
+    }
+
+    #[test]
+    fn leaves_out_the_rest_of_a_sender_that_does_not_fit() {
+        let mut genesis = Genesis::default();
+        let first = txns(KEY, &genesis.validators[1], 2);
+        let second = txns(OTHER_KEY, &genesis.validators[0], 1);
+        genesis.config.max_block_bytes = first[0].size() + second[0].size();
+        // The first sender's second transaction is padded past the limit.
+        let mut big = first[1].clone();
+        big.txn_input.signature.push_str(&"0".repeat(100));
+
+        let mut template = template(&genesis);
+        template.fill([&first[0], &big, &second[0]]);
+        assert_eq!(template.txns, vec![first[0].clone(), second[0].clone()]);
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+
+use crate::block::Block;
+
+/// Every block we have seen that links back to genesis, including blocks on
+/// branches that lost the fork choice. Blocks are keyed by hash and carry the
+/// cumulative difficulty of the branch ending in them.
+pub struct BlockTree {
+    blocks: HashMap<String, Block>,
+    weights: HashMap<String, u128>,
+}
+
+/// Fork choice: the branch with the higher cumulative difficulty wins, ties
+/// go to the lower tip hash so every node picks the same branch.
+pub fn is_heavier(weight: u128, hash: &str, other_weight: u128, other_hash: &str) -> bool {
+    weight > other_weight || (weight == other_weight && hash < other_hash)
+}
+
+pub fn chain_weight(chain: &[Block]) -> u128 {
+    chain
+        .iter()
+        .map(|block| block.header.difficulty as u128)
+        .sum()
+}
+
+impl BlockTree {
+    pub fn new(genesis: &Block) -> Self {
+        Self {
+            blocks: HashMap::from([(genesis.hash.clone(), genesis.clone())]),
+            weights: HashMap::from([(genesis.hash.clone(), genesis.header.difficulty as u128)]),
+        }
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.blocks.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &str) -> Option<&Block> {
+        self.blocks.get(hash)
+    }
+
+    pub fn at_height(&self, id: usize) -> impl Iterator<Item = &Block> {
+        self.blocks
+            .values()
+            .filter(move |block| block.header.id == id)
+    }
+
+    pub fn weight(&self, hash: &str) -> Option<u128> {
+        self.weights.get(hash).copied()
+    }
+
+    /// Adds `block` below its parent. Returns false if the parent is unknown.
+    pub fn insert(&mut self, block: Block) -> bool {
+        let weight = match self.weights.get(&block.header.previous_hash) {
+            Some(parent_weight) => parent_weight + block.header.difficulty as u128,
+            None => return false,
+        };
+        self.weights.insert(block.hash.clone(), weight);
+        self.blocks.insert(block.hash.clone(), block);
+        true
+    }
+
+    /// Removes `hash` and every block built on top of it.
+    pub fn remove_branch(&mut self, hash: &str) {
+        let mut removed = vec![hash.to_string()];
+        while let Some(hash) = removed.pop() {
+            self.blocks.remove(&hash);
+            self.weights.remove(&hash);
+            removed.extend(
+                self.blocks
+                    .values()
+                    .filter(|block| block.header.previous_hash == hash)
+                    .map(|block| block.hash.clone()),
+            );
+        }
+    }
+
+    /// Drops every branch forking off below the tip of `canonical`.
+    pub fn prune(&mut self, canonical: &[Block]) {
+        let stale: Vec<String> = self
+            .blocks
+            .values()
+            .filter(|block| {
+                canonical
+                    .get(block.header.id)
+                    .is_some_and(|canonical| canonical.hash != block.hash)
+            })
+            .map(|block| block.hash.clone())
+            .collect();
+        stale.iter().for_each(|hash| self.remove_branch(hash));
+    }
+
+    /// Blocks from genesis up to and including `hash`.
+    pub fn branch(&self, hash: &str) -> Vec<Block> {
+        let mut branch = vec![];
+        let mut current = self.blocks.get(hash);
+        while let Some(block) = current {
+            branch.push(block.clone());
+            current = self.blocks.get(&block.header.previous_hash);
+        }
+        branch.reverse();
+        branch
+    }
+}
@@ -1,102 +1,209 @@
 use chrono::prelude::*;
-use log::{info, warn};
+use log::{error, info, warn};
+
+use crate::amount::{Amount, UNIT};
+use std::collections::HashSet;
 
-use crate::account::Account;
 use crate::block;
-use crate::block::Block;
-use crate::mempool::Mempool;
-use crate::stake::Stake;
+use crate::block::{Block, BlockHeader, BlockValidationError};
+use crate::block_template::BlockTemplate;
+use crate::block_tree::{self, BlockTree};
+use crate::finality::{Finality, Vote, VoteError, CHECKPOINT_INTERVAL};
+use crate::genesis::Genesis;
+use crate::mempool::{Mempool, MempoolError, MIN_RELAY_FEE};
+use crate::merkle::MerkleProof;
+use crate::slashing::Evidence;
+use crate::state::{State, UndoJournal};
+use crate::store::{ChainStore, MemoryStore, StoreError};
 use crate::transaction::*;
-use crate::validator::Validator;
 use crate::wallet::Wallet;
 use num_bigint::BigUint;
 use sha256::digest;
+use uuid::Uuid;
 
 const BLOCK_GENERATION_INTERVAL_SECONDS: usize = 30;
 const DIFFICULTY_ADJUSTMENT_INTERVAL_BLOCKS: usize = 2;
+/// How far block and transaction timestamps may be ahead of our clock.
+pub const MAX_CLOCK_DRIFT_SECONDS: i64 = 15;
 
 pub struct Blockchain {
     pub chain: Vec<Block>,
     pub mempool: Mempool,
     pub wallet: Wallet,
-    pub accounts: Account,
-    pub stakes: Stake,
-    pub validators: Validator,
+    pub state: State,
+    pub store: Box<dyn ChainStore>,
+    pub tree: BlockTree,
+    pub finality: Finality,
+    pub genesis: Genesis,
+    // Undo journal of every block on `chain`, at the same index.
+    journals: Vec<UndoJournal>,
 }
 
 impl Blockchain {
-    pub fn new(wallet: Wallet) -> Self {
-        let genesis = Block::genesis();
-        Self {
-            chain: vec![genesis],
+    pub fn new(wallet: Wallet, genesis: Genesis) -> Self {
+        Blockchain::with_store(wallet, genesis, Box::new(MemoryStore::new()))
+            .expect("empty store can be loaded")
+    }
+
+    /// Starts from the chain in `store`, or from genesis if it is empty.
+    /// Fails rather than overwriting a stored chain that can't be read or
+    /// doesn't validate, e.g. one written for another genesis or an older
+    /// block format.
+    pub fn with_store(
+        wallet: Wallet,
+        genesis: Genesis,
+        store: Box<dyn ChainStore>,
+    ) -> Result<Self, StoreError> {
+        let block = Block::genesis(&genesis);
+        let state = State::new(&genesis);
+        let mut blockchain = Self {
+            tree: BlockTree::new(&block),
+            finality: Finality::new(&block),
+            journals: vec![state.journal(&block)],
+            chain: vec![block],
+            genesis,
             mempool: Mempool::new(),
-            wallet: wallet,
-            accounts: Account::new(),
-            stakes: Stake::new(),
-            validators: Validator::new(),
+            wallet,
+            state,
+            store,
+        };
+        blockchain.load_store()?;
+        Ok(blockchain)
+    }
+
+    fn load_store(&mut self) -> Result<(), StoreError> {
+        let chain = self.store.load_chain()?;
+        if chain.is_empty() {
+            self.reset_state();
+            return Ok(());
         }
+
+        let (state, journals) = self
+            .is_valid_chain(&chain)
+            .map_err(StoreError::InvalidChain)?;
+        info!("Loaded {} blocks from store", chain.len());
+        chain[1..].iter().for_each(|block| {
+            self.tree.insert(block.clone());
+        });
+        self.state = state;
+        self.chain = chain;
+        self.journals = journals;
+        self.check_supply();
+        Ok(())
     }
 
+    /// Signs a transaction from `sender_wallet` for this node's network.
     pub fn create_txn(
+        &self,
         sender_wallet: &mut Wallet,
         to: String,
-        amount: f64,
+        amount: Amount,
+        fee: Amount,
         txn_type: TransactionType,
-    ) -> Result<Transaction, serde_json::Error> {
-        Transaction::new(sender_wallet, to, amount, txn_type)
+        nonce: u64,
+    ) -> Transaction {
+        Transaction::new(
+            sender_wallet,
+            &self.genesis.chain_id,
+            to,
+            amount,
+            fee,
+            txn_type,
+            nonce,
+        )
     }
 
-    pub fn txn_exist(&mut self, txn: &Transaction) -> bool {
-        self.mempool.transaction_exists(txn)
+    /// Lowest fee the mempool accepts: the protocol minimum or the relay
+    /// minimum, whichever is higher.
+    pub fn min_fee(&self) -> Amount {
+        self.state.config.min_fee.max(MIN_RELAY_FEE)
     }
 
-    pub fn add_txn(&mut self, txn: Transaction) {
-        self.mempool.add_transaction(txn)
+    /// Nonce for the next transaction of `address`, counting transactions
+    /// that are still waiting in the mempool.
+    pub fn get_next_nonce(&self, address: &String) -> u64 {
+        let confirmed = self.state.accounts.get_nonce(address);
+        match self.mempool.next_nonce(address) {
+            Some(pending) => pending.max(confirmed),
+            None => confirmed,
+        }
+    }
+
+    /// Adds `txn` to the mempool if it could be included in the next block.
+    /// It is validated on the current state after the sender's pending
+    /// transactions, so it has to take the next nonce and the sender has to
+    /// afford it on top of what is already pending.
+    pub fn admit_txn(&mut self, txn: Transaction) -> Result<(), MempoolError> {
+        if self.mempool.contains(&txn.id) {
+            return Err(MempoolError::Duplicate);
+        } else if txn.txn_output.fee < self.min_fee() {
+            return Err(MempoolError::FeeTooLow {
+                minimum: self.min_fee(),
+                fee: txn.txn_output.fee,
+            });
+        }
+
+        let mut sender = self.state.sender_state(&txn.txn_input.from);
+        self.mempool
+            .pending_from(&txn.txn_input.from)
+            .into_iter()
+            .for_each(|pending| {
+                let _ = self.state.validate_pending(pending, &mut sender);
+            });
+        self.state.validate_pending(&txn, &mut sender)?;
+
+        self.mempool.add_transaction(txn, Utc::now().timestamp())
     }
 
     pub fn get_difficulty(&mut self) -> u32 {
-        let last_block = self.chain.last().unwrap();
-        if last_block.id % DIFFICULTY_ADJUSTMENT_INTERVAL_BLOCKS == 0 && last_block.id != 0 {
+        Blockchain::next_difficulty(&self.chain)
+    }
+
+    /// Difficulty required of the block following the last block of `chain`.
+    pub fn next_difficulty(chain: &[Block]) -> u32 {
+        let last_block = chain.last().unwrap();
+        if last_block.header.id % DIFFICULTY_ADJUSTMENT_INTERVAL_BLOCKS == 0
+            && last_block.header.id != 0
+        {
             let prev_difficulty_block =
-                &self.chain[self.chain.len() - 1 - DIFFICULTY_ADJUSTMENT_INTERVAL_BLOCKS];
+                &chain[chain.len() - 1 - DIFFICULTY_ADJUSTMENT_INTERVAL_BLOCKS];
 
-            let time_taken = last_block.timestamp - prev_difficulty_block.timestamp;
+            let time_taken = last_block.header.timestamp - prev_difficulty_block.header.timestamp;
             let time_expected =
                 DIFFICULTY_ADJUSTMENT_INTERVAL_BLOCKS * BLOCK_GENERATION_INTERVAL_SECONDS;
 
             if time_taken < (time_expected / 2) as i64 {
-                last_block.difficulty + 1
+                last_block.header.difficulty + 1
             } else if time_taken > (time_expected * 2) as i64 {
-                if last_block.difficulty <= 1 {
+                if last_block.header.difficulty <= 1 {
                     1
                 } else {
-                    last_block.difficulty - 1
+                    last_block.header.difficulty - 1
                 }
             } else {
-                last_block.difficulty
+                last_block.header.difficulty
             }
         } else {
-            last_block.difficulty
+            last_block.header.difficulty
         }
     }
 
     pub fn mine_block_by_stake(&mut self) -> Option<Block> {
-        if self.mempool.transactions.len() < 2 {
-            // info!("Skip mining because no transaction in mempool");
+        // Blocks are produced even with nothing pending, so validators keep
+        // earning the block reward while the network is quiet.
+        self.mempool.expire(Utc::now().timestamp());
+
+        let address = self.wallet.get_public_key();
+        if !self.state.validators.is_active(&address) {
             return None;
         }
-
-        let balance = self
-            .stakes
-            .get_balance(&self.wallet.get_public_key())
-            .clone();
+        let balance = self.state.stakes.staking_balance(&address);
 
         let difficulty = self.get_difficulty();
         info!("Mining new block with difficulty {}", difficulty);
 
         let timestamp = Utc::now().timestamp();
         let previous_hash = self.chain.last().unwrap().hash.clone();
-        let address = self.wallet.get_public_key();
 
         if Blockchain::is_staking_valid(balance, difficulty, timestamp, &previous_hash, &address) {
             Some(self.create_block(timestamp))
@@ -106,15 +213,16 @@ impl Blockchain {
     }
 
     pub fn is_staking_valid(
-        balance: u64,
+        balance: Amount,
         difficulty: u32,
         timestamp: i64,
         previous_hash: &String,
         address: &String,
     ) -> bool {
+        // Stake is weighted in base units so fractional stakes still count.
         let base = BigUint::new(vec![2]);
-        let big_balance_diff_mul = base.pow(256) * balance as u32;
-        let big_balance_diff = big_balance_diff_mul / difficulty as u64;
+        let big_balance_diff_mul = base.pow(256) * balance.units();
+        let big_balance_diff = big_balance_diff_mul / (difficulty as u64 * UNIT);
 
         let data_str = format!("{}{}{}", previous_hash, address, timestamp.to_string());
         let sha256_hash = digest(data_str);
@@ -126,172 +234,909 @@ impl Blockchain {
     pub fn create_block(&mut self, timestamp: i64) -> Block {
         info!("Creating new block...");
 
+        let validator = self.wallet.get_public_key();
+        let mut template = BlockTemplate::new(self.state.clone(), self.chain.len(), validator);
+        template.fill(self.mempool.by_priority());
+
         Block::new(
-            self.chain.len(),
-            self.chain.last().unwrap().hash.clone(),
+            self.chain.last().unwrap(),
             timestamp,
-            self.mempool.transactions.clone(),
-            self.get_difficulty(),
+            template.txns,
+            template.state.root(),
+            Blockchain::next_difficulty(&self.chain),
             self.wallet.clone(),
         )
     }
 
-    pub fn is_valid_block(&mut self, block: Block) -> bool {
-        let prev_block = self.chain.last().unwrap();
+    /// Validates `block` as the next block of the local chain and, if it is
+    /// valid, executes and appends it.
+    pub fn is_valid_block(&mut self, block: Block) -> Result<(), BlockValidationError> {
+        let mut state = self.state.clone();
+        let journal = Blockchain::validate_block(&self.chain, &block, &mut state)?;
 
-        if block.previous_hash != prev_block.hash {
-            warn!(
-                "block with id: {} has wrong previous hash {} vs {} ",
-                block.id, block.previous_hash, prev_block.hash
-            );
+        self.state = state;
+        self.push_block(block, journal);
+        Ok(())
+    }
 
-            return false;
-        } else if block.hash
-            != block::calculate_hash(
-                &block.id,
-                &block.timestamp,
-                &block.previous_hash,
-                &block.txn,
-                &block.validator,
-                &block.difficulty,
-            )
+    /// Adds a block received from the network. A block on our tip is
+    /// executed right away, a block on another branch is executed on that
+    /// branch, kept in the block tree and triggers a reorg once its branch
+    /// outweighs ours. Returns whether the block is now our tip.
+    pub fn add_block(&mut self, block: Block) -> Result<bool, BlockValidationError> {
+        if block.header.previous_hash == self.chain.last().unwrap().hash {
+            return self.is_valid_block(block).map(|()| true);
+        }
+
+        if block.header.id <= self.finality.height {
+            return Err(BlockValidationError::ConflictsWithFinalized(
+                self.finality.height,
+            ));
+        }
+        let parent = self.tree.get(&block.header.previous_hash).ok_or_else(|| {
+            BlockValidationError::UnknownParent(block.header.previous_hash.clone())
+        })?;
+        Blockchain::check_header(parent, &block)?;
+
+        let branch = self.tree.branch(&block.header.previous_hash);
+        let (fork_point, mut state) = self.fork_state(&branch).map_err(|(_, e)| e)?;
+        Blockchain::execute_from(&branch, fork_point, &mut state).map_err(|(_, e)| e)?;
+        Blockchain::validate_block(&branch, &block, &mut state)?;
+
+        let hash = block.hash.clone();
+        self.tree.insert(block);
+        self.choose_fork(&hash)
+    }
+
+    /// Looks for another block by the same validator at the same height as
+    /// `block`. If there is one and the validator can still be slashed,
+    /// returns an evidence transaction signed by our wallet, already added
+    /// to the mempool.
+    pub fn detect_equivocation(&mut self, block: &Block) -> Option<Transaction> {
+        if block.hash != block::calculate_hash(&block.header)
+            || !Block::verify_block_signature(block)
         {
-            warn!("block with id: {} has invalid hash", block.id);
-            return false;
-        } else if prev_block.id + 1 != block.id {
-            warn!(
-                "block with id: {} is not the next block after the latest: {}",
-                block.id, prev_block.id
-            );
-            return false;
-        } else if !Block::verify_block_signature(&block) {
-            warn!(
-                "block with id: {} has invalid validator signature",
-                block.id
-            );
-            return false;
+            return None;
+        }
+        let other = self.tree.at_height(block.header.id).find(|other| {
+            other.header.validator == block.header.validator && other.hash != block.hash
+        })?;
+        let evidence = Evidence::new(other, block);
+
+        let reported = self.mempool.iter().any(|txn| {
+            txn.evidence
+                .as_ref()
+                .is_some_and(|pending| pending.offender() == evidence.offender())
+        });
+        if reported {
+            return None;
+        }
+
+        let address = self.wallet.get_public_key();
+        let nonce = self.get_next_nonce(&address);
+        let fee = self.min_fee();
+        let txn = Transaction::new_evidence(
+            &mut self.wallet,
+            &self.genesis.chain_id,
+            evidence,
+            fee,
+            nonce,
+        );
+        match self.admit_txn(txn.clone()) {
+            Ok(()) => {
+                warn!(
+                    "Validator {} signed two blocks at height {}",
+                    block.header.validator, block.header.id
+                );
+                Some(txn)
+            }
+            Err(e) => {
+                info!("Not reporting equivocation: {}", e);
+                None
+            }
+        }
+    }
+
+    // Switches to the branch ending in `hash` if it beats the current tip.
+    // An invalid block on the branch is dropped from the tree together with
+    // everything built on it. Returns whether we switched.
+    fn choose_fork(&mut self, hash: &str) -> Result<bool, BlockValidationError> {
+        let tip = self.chain.last().unwrap();
+        let weight = self.tree.weight(hash).unwrap_or_default();
+        let tip_weight = self.tree.weight(&tip.hash).unwrap_or_default();
+        if !block_tree::is_heavier(weight, hash, tip_weight, &tip.hash) {
+            info!("Keeping block {} on a side branch", hash);
+            return Ok(false);
+        }
+
+        let branch = self.tree.branch(hash);
+        self.reorg(&branch).map(|()| true).map_err(|(height, e)| {
+            self.tree.remove_branch(&branch[height].hash);
+            e
+        })
+    }
+
+    // Switches to `chain`, which has to start at our genesis. The state is
+    // rolled back to the fork point with the undo journals of the abandoned
+    // blocks, so only the new blocks are executed. On error returns the
+    // height of the offending block; the chain and state are left untouched.
+    fn reorg(&mut self, chain: &[Block]) -> Result<(), (usize, BlockValidationError)> {
+        let (fork_point, mut state) = self.fork_state(chain)?;
+
+        // Keep our own copy of the shared blocks, their transactions were
+        // checked when we added them.
+        let mut new_chain = self.chain[..fork_point].to_vec();
+        new_chain.extend_from_slice(&chain[fork_point..]);
+        let journals = Blockchain::execute_from(&new_chain, fork_point, &mut state)?;
+
+        self.switch_chain(new_chain, state, journals, fork_point);
+        Ok(())
+    }
+
+    // Finds where `chain`, which has to start at our genesis, leaves ours and
+    // returns that height with the state after the last shared block, rolled
+    // back with the undo journals of our blocks past it.
+    fn fork_state(&self, chain: &[Block]) -> Result<(usize, State), (usize, BlockValidationError)> {
+        let fork_point = self
+            .chain
+            .iter()
+            .zip(chain)
+            .take_while(|(current, new)| current.hash == new.hash)
+            .count();
+        if fork_point == 0 {
+            return Err((0, BlockValidationError::InvalidGenesis));
+        } else if fork_point <= self.finality.height {
+            return Err((
+                fork_point,
+                BlockValidationError::ConflictsWithFinalized(self.finality.height),
+            ));
+        }
+
+        let mut state = self.state.clone();
+        self.journals[fork_point..]
+            .iter()
+            .rev()
+            .for_each(|journal| state.revert(journal));
+        Ok((fork_point, state))
+    }
+
+    // Validates and executes the blocks of `chain` from `height` onwards on
+    // `state`, which must be the state after the block below `height`.
+    fn execute_from(
+        chain: &[Block],
+        height: usize,
+        state: &mut State,
+    ) -> Result<Vec<UndoJournal>, (usize, BlockValidationError)> {
+        (height..chain.len())
+            .map(|i| Blockchain::validate_block(&chain[..i], &chain[i], state).map_err(|e| (i, e)))
+            .collect()
+    }
+
+    /// Checks `block` against the last block of `chain` and executes it on
+    /// `state`, which must be the state after `chain`. Returns the journal to
+    /// undo the block. On error `state` may be partially updated and should
+    /// be discarded.
+    pub fn validate_block(
+        chain: &[Block],
+        block: &Block,
+        state: &mut State,
+    ) -> Result<UndoJournal, BlockValidationError> {
+        Blockchain::check_header(chain.last().unwrap(), block)?;
+
+        let journal = state.journal(block);
+        let difficulty = Blockchain::next_difficulty(chain);
+        if block.header.difficulty != difficulty {
+            return Err(BlockValidationError::InvalidDifficulty {
+                expected: difficulty,
+                found: block.header.difficulty,
+            });
+        } else if !state.validators.is_active(&block.header.validator) {
+            return Err(BlockValidationError::NotValidator(
+                block.header.validator.clone(),
+            ));
         } else if !Blockchain::is_staking_valid(
-            self.stakes.get_balance(&block.validator).clone(),
-            block.difficulty,
-            block.timestamp,
-            &block.previous_hash,
-            &block.validator,
+            state.stakes.staking_balance(&block.header.validator),
+            block.header.difficulty,
+            block.header.timestamp,
+            &block.header.previous_hash,
+            &block.header.validator,
         ) {
-            warn!("block with id: {} has invalid validator", block.id);
-            return false;
+            return Err(BlockValidationError::InvalidStakeProof);
+        } else if block.txn.len() > state.config.max_block_txns {
+            return Err(BlockValidationError::TooManyTransactions {
+                max: state.config.max_block_txns,
+                found: block.txn.len(),
+            });
+        }
+        let bytes = block.txn_bytes();
+        if bytes > state.config.max_block_bytes {
+            return Err(BlockValidationError::TooLarge {
+                max: state.config.max_block_bytes,
+                found: bytes,
+            });
         }
 
-        self.add_new_block(block);
-        true
+        state.apply_block(block)?;
+        if state.root() != block.header.state_root {
+            return Err(BlockValidationError::StateRootMismatch {
+                expected: state.root(),
+                found: block.header.state_root.clone(),
+            });
+        }
+        Ok(journal)
+    }
+
+    /// Checks of `block` as the successor of `prev_block` that don't need the
+    /// state: linkage, hashes, timestamp and signature.
+    pub fn check_header(prev_block: &Block, block: &Block) -> Result<(), BlockValidationError> {
+        if block.header.chain_id != prev_block.header.chain_id {
+            return Err(BlockValidationError::WrongChain(
+                block.header.chain_id.clone(),
+            ));
+        } else if block.header.previous_hash != prev_block.hash {
+            return Err(BlockValidationError::WrongPreviousHash {
+                expected: prev_block.hash.clone(),
+                found: block.header.previous_hash.clone(),
+            });
+        } else if prev_block.header.id + 1 != block.header.id {
+            return Err(BlockValidationError::NonSequentialId {
+                expected: prev_block.header.id + 1,
+                found: block.header.id,
+            });
+        } else if block.hash != block::calculate_hash(&block.header) {
+            return Err(BlockValidationError::InvalidHash);
+        } else if block.header.tx_root != block::calculate_tx_root(&block.txn) {
+            return Err(BlockValidationError::InvalidTxRoot);
+        } else if block.header.timestamp < prev_block.header.timestamp
+            || block.header.timestamp > Utc::now().timestamp() + MAX_CLOCK_DRIFT_SECONDS
+        {
+            return Err(BlockValidationError::TimestampOutOfRange(
+                block.header.timestamp,
+            ));
+        } else if !Block::verify_block_signature(block) {
+            return Err(BlockValidationError::InvalidSignature);
+        }
+        Ok(())
     }
 
     pub fn add_new_block(&mut self, block: Block) {
-        self.execute_txn(&block);
+        let journal = self.execute_txn(&block);
+        self.push_block(block, journal);
+    }
+
+    // Logs an error if the coins held no longer add up to the total supply
+    // after executing the chain up to the tip.
+    fn check_supply(&self) {
+        if let Err(e) = self.state.supply().check() {
+            error!(
+                "Supply invariant broken after block {}: {}",
+                self.chain.last().unwrap().header.id,
+                e
+            );
+        }
+    }
+
+    fn push_block(&mut self, block: Block, journal: UndoJournal) {
         info!("Add new block to current chain");
+        block.txn.iter().for_each(|txn| {
+            self.mempool.remove(&txn.id);
+        });
+        self.revalidate_mempool();
+        if let Err(e) = self.store.append(&block) {
+            error!("Failed to persist block {}: {}", block.header.id, e);
+        }
+        self.tree.insert(block.clone());
         self.chain.push(block);
-        self.mempool.clear();
+        self.journals.push(journal);
+        self.check_supply();
+        self.update_finality();
     }
 
-    pub fn verify_leader(&mut self, block: &Block) -> bool {
-        self.stakes.get_max(&self.validators.accounts) == block.validator
+    // Drops pending transactions that can no longer go into the next block,
+    // checking each sender's on the current state in nonce order like
+    // `admit_txn`. A sender's transactions after a dropped one go too, they
+    // would fail on the nonce.
+    fn revalidate_mempool(&mut self) {
+        let mut invalid = vec![];
+        for address in self.mempool.senders() {
+            let mut sender = self.state.sender_state(address);
+            for txn in self.mempool.pending_from(address) {
+                if let Err(e) = self.state.validate_pending(txn, &mut sender) {
+                    info!("Dropping transaction {} from the mempool: {}", txn.id, e);
+                    invalid.push(txn.id);
+                }
+            }
+        }
+        invalid.iter().for_each(|id| {
+            self.mempool.remove(id);
+        });
     }
 
-    pub fn get_leader(&mut self) -> String {
-        self.stakes.get_max(&self.validators.accounts)
+    /// Records a checkpoint vote from the network and finalizes the
+    /// checkpoint if it now has enough votes.
+    pub fn add_vote(&mut self, vote: Vote) -> Result<(), VoteError> {
+        self.finality
+            .add_vote(vote, self.chain.len(), &self.state)?;
+        self.update_finality();
+        Ok(())
+    }
+
+    /// Signs a vote for the tip if it is a checkpoint and our wallet is a
+    /// validator that hasn't voted at this height yet.
+    pub fn checkpoint_vote(&mut self) -> Option<Vote> {
+        let tip = self.chain.last().unwrap();
+        let address = self.wallet.get_public_key();
+        if tip.header.id == 0
+            || !tip.header.id.is_multiple_of(CHECKPOINT_INTERVAL)
+            || !self.state.validators.is_active(&address)
+            || self.finality.has_voted(tip.header.id, &address)
+        {
+            return None;
+        }
+
+        let vote = Vote::new(
+            &self.genesis.chain_id,
+            tip.header.id,
+            tip.hash.clone(),
+            self.wallet.clone(),
+        );
+        match self.add_vote(vote.clone()) {
+            Ok(()) => Some(vote),
+            Err(e) => {
+                warn!("Failed to vote for checkpoint {}: {}", vote.height, e);
+                None
+            }
+        }
     }
 
-    pub fn replace_chain(&mut self, chain: &Vec<Block>) {
-        if chain.len() <= self.chain.len() {
-            warn!("Received chain is not longer than the current chain");
-            return;
-        } else if !self.is_valid_chain(chain) {
-            warn!("Received chain is invalid");
-            return;
+    fn update_finality(&mut self) {
+        if self.finality.update(&self.chain, &self.state) {
+            info!(
+                "Finalized block {} at height {}",
+                self.finality.hash, self.finality.height
+            );
+            self.tree.prune(&self.chain[..=self.finality.height]);
         }
+    }
 
-        info!("Replacing current chain with new chain");
+    pub fn verify_leader(&mut self, block: &Block) -> bool {
+        self.state.stakes.get_max(&self.state.validators.accounts) == block.header.validator
+    }
 
-        self.reset_state();
-        self.execute_chain(chain);
-        self.chain = chain.clone();
+    pub fn get_leader(&mut self) -> String {
+        self.state.stakes.get_max(&self.state.validators.accounts)
     }
 
-    pub fn is_valid_chain(&mut self, chain: &Vec<Block>) -> bool {
-        if *chain.first().unwrap() != Block::genesis() {
-            return false;
+    /// Replaces the local chain with `chain` if it wins the fork choice.
+    /// Blocks past the fork point are executed after rolling the state back;
+    /// the current chain and state are kept if any block fails.
+    pub fn replace_chain(&mut self, chain: &[Block]) -> Result<(), BlockValidationError> {
+        let tip = chain.last().ok_or(BlockValidationError::InvalidGenesis)?;
+        let current_tip = self.chain.last().unwrap();
+        if !block_tree::is_heavier(
+            block_tree::chain_weight(chain),
+            &tip.hash,
+            block_tree::chain_weight(&self.chain),
+            &current_tip.hash,
+        ) {
+            info!("Received chain is not heavier than the current chain");
+            return Ok(());
         }
+        self.reorg(chain).map_err(|(_, e)| e)
+    }
 
-        for i in 0..chain.len() {
-            if i == 0 {
-                continue;
-            };
+    // Makes `chain` the canonical chain. Transactions of abandoned blocks go
+    // back to the mempool, pending transactions that no longer apply are
+    // dropped.
+    fn switch_chain(
+        &mut self,
+        chain: Vec<Block>,
+        state: State,
+        journals: Vec<UndoJournal>,
+        fork_point: usize,
+    ) {
+        info!(
+            "Switching to chain with tip {} forked at height {}",
+            chain.last().unwrap().hash,
+            fork_point
+        );
 
-            let block = &chain[i];
-            let prev_block = &chain[i - 1];
+        let included: HashSet<Uuid> = chain[fork_point..]
+            .iter()
+            .flat_map(|block| block.txn.iter().map(|txn| txn.id))
+            .collect();
+        let abandoned: Vec<Transaction> = self.chain[fork_point..]
+            .iter()
+            .flat_map(|block| block.txn.iter().cloned())
+            .filter(|txn| !included.contains(&txn.id))
+            .collect();
 
-            if prev_block.hash != block.previous_hash {
-                warn!("block with id: {} has wrong previous hash", block.id);
-                return false;
-            } else if prev_block.id + 1 != block.id {
-                warn!(
-                    "block with id: {} is not the next block after the latest: {}",
-                    block.id, prev_block.id
-                );
-                return false;
+        chain[fork_point..].iter().for_each(|block| {
+            self.tree.insert(block.clone());
+        });
+        self.state = state;
+        self.chain = chain;
+        self.journals.truncate(fork_point);
+        self.journals.extend(journals);
+        self.check_supply();
+        let now = Utc::now().timestamp();
+        for txn in abandoned {
+            let id = txn.id;
+            if let Err(e) = self.mempool.add_transaction(txn, now) {
+                info!("Not returning transaction {} to the mempool: {}", id, e);
             }
         }
-        true
+        self.revalidate_mempool();
+
+        if let Err(e) = self.persist_chain(fork_point) {
+            error!("Failed to persist replaced chain: {}", e);
+        }
+        self.update_finality();
+    }
+
+    /// Replays `chain` on a fresh genesis state, applying the same checks as
+    /// `is_valid_block` to every block. Returns the resulting state and the
+    /// undo journal of every block.
+    pub fn is_valid_chain(
+        &self,
+        chain: &[Block],
+    ) -> Result<(State, Vec<UndoJournal>), BlockValidationError> {
+        match chain.first() {
+            Some(genesis)
+                if genesis.hash == self.chain[0].hash
+                    && genesis.hash == block::calculate_hash(&genesis.header) => {}
+            _ => return Err(BlockValidationError::InvalidGenesis),
+        }
+
+        let mut state = State::new(&self.genesis);
+        let mut journals = vec![state.journal(&chain[0])];
+        journals.extend(Blockchain::execute_from(chain, 1, &mut state).map_err(|(_, e)| e)?);
+        Ok((state, journals))
     }
 
     pub fn reset_state(&mut self) {
-        let genesis = Block::genesis();
+        let genesis = Block::genesis(&self.genesis);
+        self.tree = BlockTree::new(&genesis);
+        self.finality = Finality::new(&genesis);
+        self.state = State::new(&self.genesis);
+        self.journals = vec![self.state.journal(&genesis)];
         self.chain = vec![genesis];
-        self.accounts = Account::new();
-        self.stakes = Stake::new();
-        self.validators = Validator::new();
+        if let Err(e) = self.persist_chain(0) {
+            error!("Failed to reset chain store: {}", e);
+        }
     }
 
-    pub fn execute_chain(&mut self, chain: &Vec<Block>) {
-        chain.iter().for_each(|block| self.execute_txn(block));
+    // Rewrites the store from `height` onwards so it matches `self.chain`.
+    fn persist_chain(&mut self, height: usize) -> Result<(), StoreError> {
+        self.store.truncate(height)?;
+        for block in &self.chain[height..] {
+            self.store.append(block)?;
+        }
+        Ok(())
     }
 
-    pub fn execute_txn(&mut self, block: &Block) {
-        block.txn.iter().for_each(|txn| match txn.txn_type {
-            TransactionType::TRANSACTION => {
-                // Transfer amount
-                self.accounts.transfer(
-                    &txn.txn_input.from,
-                    &txn.txn_output.to,
-                    &txn.txn_output.amount,
+    /// Executes the transactions of `block`, skipping failing ones, and
+    /// returns the journal to undo them.
+    pub fn execute_txn(&mut self, block: &Block) -> UndoJournal {
+        let journal = self.state.journal(block);
+        if let Err(e) = self
+            .state
+            .begin_block(block.header.id, &block.header.validator)
+        {
+            warn!("starting block {} failed: {:?}", block.header.id, e);
+        }
+        block.txn.iter().for_each(|txn| {
+            if let Err(e) = self.state.apply_txn(txn, &block.header.validator) {
+                warn!(
+                    "transaction {} in block {} failed: {}",
+                    txn.id, block.header.id, e
                 );
-                // Transfer fee
-                self.accounts
-                    .transfer(&txn.txn_input.from, &block.validator, &txn.txn_output.fee);
-            }
-            TransactionType::STAKE => {
-                self.stakes.update(&txn);
-                self.accounts
-                    .decrement(&txn.txn_input.from, &txn.txn_output.amount);
-                // Transfer fee
-                self.accounts
-                    .transfer(&txn.txn_input.from, &block.validator, &txn.txn_output.fee);
-            }
-            TransactionType::VALIDATOR => {
-                if self.validators.update(&txn) {
-                    self.accounts
-                        .decrement(&txn.txn_input.from, &txn.txn_output.amount);
-                    // Transfer fee
-                    self.accounts.transfer(
-                        &txn.txn_input.from,
-                        &block.validator,
-                        &txn.txn_output.fee,
-                    );
-                }
             }
         });
+        journal
+    }
+
+    /// Finds `id` on the local chain and returns the block header together
+    /// with a Merkle proof of the transaction's inclusion.
+    pub fn get_txn_proof(&self, id: &Uuid) -> Option<(BlockHeader, Transaction, MerkleProof)> {
+        self.chain.iter().rev().find_map(|block| {
+            let proof = block.prove_txn(id)?;
+            let txn = block.txn[proof.index].clone();
+            Some((block.header.clone(), txn, proof))
+        })
+    }
+
+    pub fn get_block(&self, key: &str) -> Result<Option<Block>, StoreError> {
+        match key.parse::<usize>() {
+            Ok(height) => self.store.get_by_height(height),
+            Err(_) => self.store.get_by_hash(key),
+        }
+    }
+
+    pub fn get_balance(&self, public_key: &String) -> Amount {
+        self.state.accounts.get_balance(public_key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{TransactionError, SYSTEM_ADDRESS};
+
+    const KEY: &str = "27a23bf39574e86464f4e638241b3ef3dd223d9a30bd97810ff29c992e747e5a230681c76f00b412ccf7757a8449c448a04acd735e497a7612b66d8bfcb8e576";
+    const OTHER_KEY: &str = "5ae5066dd048ffb8f8628c44324e63c7b8782a026009a85a96935acb4921abbc5aede624154386ca358af195e13a46981b917ee8279f30a67d7a211a3d3e7243";
+
+    fn wallet() -> Wallet {
+        Wallet::get_wallet(KEY.to_string())
+    }
+
+    fn other_wallet() -> Wallet {
+        Wallet::get_wallet(OTHER_KEY.to_string())
+    }
+
+    // The first timestamp after `after` the stake of `blockchain`'s wallet
+    // wins the next block at.
+    fn staking_timestamp(blockchain: &mut Blockchain, after: i64) -> i64 {
+        let address = blockchain.wallet.get_public_key();
+        let balance = blockchain.state.stakes.staking_balance(&address);
+        let difficulty = blockchain.get_difficulty();
+        let previous_hash = blockchain.chain.last().unwrap().hash.clone();
+        (after + 1..)
+            .find(|timestamp| {
+                Blockchain::is_staking_valid(
+                    balance,
+                    difficulty,
+                    *timestamp,
+                    &previous_hash,
+                    &address,
+                )
+            })
+            .unwrap()
+    }
+
+    // Creates the next block of `blockchain` at the first timestamp after
+    // `after` its wallet's stake wins.
+    fn mine(blockchain: &mut Blockchain, after: i64) -> Block {
+        let timestamp = staking_timestamp(blockchain, after);
+        blockchain.create_block(timestamp)
+    }
+
+    // Mines and adds `count` blocks on `blockchain`, about one every
+    // `BLOCK_GENERATION_INTERVAL_SECONDS` so the difficulty stays put.
+    fn extend(blockchain: &mut Blockchain, count: usize) -> Vec<Block> {
+        (0..count)
+            .map(|_| {
+                let after = blockchain.chain.last().unwrap().header.timestamp
+                    + BLOCK_GENERATION_INTERVAL_SECONDS as i64
+                    - 1;
+                let block = mine(blockchain, after);
+                blockchain.add_new_block(block.clone());
+                block
+            })
+            .collect()
+    }
+
+    #[test]
+    fn keeps_a_stored_chain_for_another_genesis() {
+        let other = Genesis {
+            chain_id: String::from("pos-testnet"),
+            ..Genesis::default()
+        };
+        let mut store = MemoryStore::new();
+        store.append(&Block::genesis(&other)).unwrap();
+
+        let result = Blockchain::with_store(wallet(), Genesis::default(), Box::new(store));
+        assert!(matches!(
+            result,
+            Err(StoreError::InvalidChain(
+                BlockValidationError::InvalidGenesis
+            ))
+        ));
+    }
+
+    #[test]
+    fn writes_genesis_to_an_empty_store() {
+        let blockchain =
+            Blockchain::with_store(wallet(), Genesis::default(), Box::new(MemoryStore::new()))
+                .unwrap();
+        assert_eq!(blockchain.store.height(), 1);
+        assert_eq!(
+            blockchain.store.get_by_height(0).unwrap().unwrap().hash,
+            Block::genesis(&Genesis::default()).hash
+        );
+    }
+
+    #[test]
+    fn reports_two_blocks_signed_at_the_same_height() {
+        let genesis = Genesis::default();
+        let mut producer = Blockchain::new(wallet(), genesis.clone());
+        let first = mine(&mut producer, genesis.timestamp);
+        let second = mine(&mut producer, first.header.timestamp);
+        let mut observer = Blockchain::new(other_wallet(), genesis);
+
+        assert!(observer.detect_equivocation(&first).is_none());
+        assert!(observer.add_block(first.clone()).unwrap());
+        let mut forged = second.clone();
+        forged.signature = first.signature.clone();
+        assert!(observer.detect_equivocation(&forged).is_none());
+
+        let report = observer.detect_equivocation(&second).unwrap();
+        let evidence = report.evidence.as_ref().unwrap();
+        assert_eq!(evidence.offender(), &first.header.validator);
+        assert_eq!(evidence.height(), 1);
+        assert!(observer.mempool.contains(&report.id));
+        // Once reported it isn't reported again.
+        assert!(observer.detect_equivocation(&second).is_none());
     }
 
-    pub fn get_balance(&mut self, public_key: &String) -> &f64 {
-        self.accounts.get_balance(public_key)
+    #[test]
+    fn rewards_an_empty_block() {
+        let genesis = Genesis::default();
+        let mut producer = Blockchain::new(wallet(), genesis.clone());
+        let block = mine(&mut producer, genesis.timestamp);
+        assert!(block.txn.is_empty());
+
+        let mut node = Blockchain::new(other_wallet(), genesis);
+        let supply = node.state.total_supply;
+        assert!(node.add_block(block.clone()).unwrap());
+        assert_eq!(
+            node.state.accounts.get_balance(&block.header.validator),
+            Amount::from_coins(501)
+        );
+        assert_eq!(
+            node.state.total_supply,
+            supply.checked_add(Amount::from_coins(1)).unwrap()
+        );
+    }
+
+    #[test]
+    fn keeps_a_lighter_branch_on_the_side() {
+        let genesis = Genesis::default();
+        let mut node = Blockchain::new(wallet(), genesis.clone());
+        let mut other = Blockchain::new(other_wallet(), genesis);
+        let ours = extend(&mut node, 2);
+        let theirs = extend(&mut other, 1);
+        let root = node.state.root();
+
+        assert!(!node.add_block(theirs[0].clone()).unwrap());
+        assert!(node.tree.contains(&theirs[0].hash));
+        assert_eq!(node.chain.last().unwrap().hash, ours[1].hash);
+        assert_eq!(node.state.root(), root);
+    }
+
+    #[test]
+    fn switches_to_a_heavier_branch() {
+        let genesis = Genesis::default();
+        let mut node = Blockchain::new(wallet(), genesis.clone());
+        let mut other = Blockchain::new(other_wallet(), genesis);
+        extend(&mut node, 1);
+        let theirs = extend(&mut other, 2);
+
+        // The first block only ties, which one wins depends on the hashes.
+        node.add_block(theirs[0].clone()).unwrap();
+        assert!(node.add_block(theirs[1].clone()).unwrap());
+        assert_eq!(node.chain, other.chain);
+        assert_eq!(node.state.root(), other.state.root());
+    }
+
+    #[test]
+    fn breaks_ties_by_the_lower_hash() {
+        let genesis = Genesis::default();
+        let mut first = Blockchain::new(wallet(), genesis.clone());
+        let mut second = Blockchain::new(other_wallet(), genesis);
+        let a = extend(&mut first, 1).remove(0);
+        let b = extend(&mut second, 1).remove(0);
+        assert_ne!(a.hash, b.hash);
+
+        let a_wins = a.hash < b.hash;
+        assert_eq!(first.add_block(b.clone()).unwrap(), !a_wins);
+        assert_eq!(second.add_block(a.clone()).unwrap(), a_wins);
+        assert_eq!(
+            first.chain.last().unwrap().hash,
+            second.chain.last().unwrap().hash
+        );
+        assert_eq!(first.state.root(), second.state.root());
+    }
+
+    #[test]
+    fn reorgs_through_the_undo_journals() {
+        let genesis = Genesis::default();
+        let mut node = Blockchain::new(wallet(), genesis.clone());
+        let mut other = Blockchain::new(other_wallet(), genesis.clone());
+        let transfer = |wallet: &mut Wallet, to: String| {
+            Transaction::new(
+                wallet,
+                &genesis.chain_id,
+                to,
+                Amount::from_coins(5),
+                genesis.config.min_fee,
+                TransactionType::TRANSACTION,
+                0,
+            )
+        };
+        let ours = transfer(&mut wallet(), other_wallet().get_public_key());
+        let theirs = transfer(&mut other_wallet(), wallet().get_public_key());
+        node.admit_txn(ours.clone()).unwrap();
+        other.admit_txn(theirs).unwrap();
+        assert_eq!(extend(&mut node, 1)[0].txn, vec![ours.clone()]);
+        let branch = extend(&mut other, 2);
+
+        node.add_block(branch[0].clone()).unwrap();
+        assert!(node.add_block(branch[1].clone()).unwrap());
+        assert_eq!(node.chain, other.chain);
+        assert_eq!(node.state.root(), other.state.root());
+        assert_eq!(node.journals.len(), node.chain.len());
+        // The abandoned block's transaction is pending again.
+        assert!(node.mempool.contains(&ours.id));
+
+        let mut state = node.state.clone();
+        node.journals[1..]
+            .iter()
+            .rev()
+            .for_each(|journal| state.revert(journal));
+        assert_eq!(state.root(), State::new(&genesis).root());
+    }
+
+    #[test]
+    fn drops_an_invalid_branch() {
+        let genesis = Genesis::default();
+        let mut node = Blockchain::new(wallet(), genesis.clone());
+        let mut other = Blockchain::new(other_wallet(), genesis);
+        extend(&mut node, 1);
+        let branch = extend(&mut other, 2);
+        let root = node.state.root();
+
+        // Blocks are checked before they go into the tree, put a bad one
+        // there directly.
+        let mut bad = branch[1].clone();
+        bad.header.state_root = root.clone();
+        bad.hash = block::calculate_hash(&bad.header);
+        let mut child = bad.clone();
+        child.header.id += 1;
+        child.header.previous_hash = bad.hash.clone();
+        child.hash = block::calculate_hash(&child.header);
+        node.tree.insert(branch[0].clone());
+        node.tree.insert(bad.clone());
+        node.tree.insert(child.clone());
+
+        assert_eq!(
+            node.choose_fork(&child.hash),
+            Err(BlockValidationError::InvalidSignature)
+        );
+        assert!(!node.tree.contains(&bad.hash));
+        assert!(!node.tree.contains(&child.hash));
+        assert!(node.tree.contains(&branch[0].hash));
+        assert_eq!(node.chain.len(), 2);
+        assert_eq!(node.state.root(), root);
+    }
+
+    #[test]
+    fn rejects_blocks_from_an_exited_validator() {
+        let genesis = Genesis::default();
+        let mut node = Blockchain::new(wallet(), genesis.clone());
+        let exit = Transaction::new(
+            &mut wallet(),
+            &genesis.chain_id,
+            String::from(SYSTEM_ADDRESS),
+            Amount::ZERO,
+            genesis.config.min_fee,
+            TransactionType::EXIT,
+            0,
+        );
+        node.admit_txn(exit).unwrap();
+        extend(&mut node, 1);
+        let address = node.wallet.get_public_key();
+        assert!(!node.state.validators.is_active(&address));
+
+        let tip = node.chain.last().unwrap().header.timestamp;
+        let block = mine(&mut node, tip);
+        assert_eq!(
+            node.add_block(block),
+            Err(BlockValidationError::NotValidator(address))
+        );
+        assert_eq!(node.chain.len(), 2);
+    }
+
+    #[test]
+    fn rejects_a_replayed_transaction() {
+        let genesis = Genesis::default();
+        let mut node = Blockchain::new(wallet(), genesis.clone());
+        let txn = Transaction::new(
+            &mut wallet(),
+            &genesis.chain_id,
+            other_wallet().get_public_key(),
+            Amount::from_coins(5),
+            genesis.config.min_fee,
+            TransactionType::TRANSACTION,
+            0,
+        );
+        node.admit_txn(txn.clone()).unwrap();
+        assert_eq!(extend(&mut node, 1)[0].txn, vec![txn.clone()]);
+
+        assert_eq!(
+            node.admit_txn(txn.clone()),
+            Err(MempoolError::Invalid(TransactionError::InvalidNonce {
+                expected: 1,
+                actual: 0,
+            }))
+        );
+        let validator = node.wallet.get_public_key();
+        assert_eq!(
+            node.state.clone().apply_txn(&txn, &validator),
+            Err(TransactionError::InvalidNonce {
+                expected: 1,
+                actual: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn keeps_the_chain_when_a_received_block_is_invalid() {
+        let genesis = Genesis::default();
+        let mut node = Blockchain::new(wallet(), genesis.clone());
+        let mut other = Blockchain::new(other_wallet(), genesis);
+        extend(&mut node, 1);
+        extend(&mut other, 3);
+        let (chain, root) = (node.chain.clone(), node.state.root());
+
+        let mut bad = other.chain.clone();
+        bad[2].header.state_root = root.clone();
+        bad[2].hash = block::calculate_hash(&bad[2].header);
+        assert_eq!(
+            node.replace_chain(&bad),
+            Err(BlockValidationError::InvalidSignature)
+        );
+        assert_eq!(node.chain, chain);
+        assert_eq!(node.state.root(), root);
+        assert_eq!(node.journals.len(), chain.len());
+
+        node.replace_chain(&other.chain).unwrap();
+        assert_eq!(node.chain, other.chain);
+        assert_eq!(node.state.root(), other.state.root());
+    }
+
+    #[test]
+    fn rejects_fees_below_the_minimum() {
+        let mut genesis = Genesis::default();
+        genesis.config.min_fee = Amount::from_coins(2);
+        let mut node = Blockchain::new(wallet(), genesis.clone());
+        let cheap = Transaction::new(
+            &mut wallet(),
+            &genesis.chain_id,
+            other_wallet().get_public_key(),
+            Amount::from_coins(5),
+            Amount::from_coins(1),
+            TransactionType::TRANSACTION,
+            0,
+        );
+        assert_eq!(
+            node.admit_txn(cheap.clone()),
+            Err(MempoolError::FeeTooLow {
+                minimum: Amount::from_coins(2),
+                fee: Amount::from_coins(1),
+            })
+        );
+
+        let timestamp = staking_timestamp(&mut node, genesis.timestamp);
+        let previous = node.chain.last().unwrap().clone();
+        let block = Block::new(
+            &previous,
+            timestamp,
+            vec![cheap],
+            node.state.root(),
+            node.get_difficulty(),
+            wallet(),
+        );
+        assert_eq!(
+            node.add_block(block),
+            Err(BlockValidationError::InvalidTransaction {
+                index: 0,
+                error: TransactionError::FeeTooLow {
+                    minimum: Amount::from_coins(2),
+                    fee: Amount::from_coins(1),
+                },
+            })
+        );
+        assert_eq!(node.chain.len(), 1);
     }
 }
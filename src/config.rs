@@ -0,0 +1,126 @@
+use serde::{Deserialize, Serialize};
+
+use crate::amount::Amount;
+
+/// Protocol parameters every node on a network has to agree on.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ChainConfig {
+    /// Blocks between an UNSTAKE transaction and the release of its funds.
+    pub unbonding_period: usize,
+    /// Blocks between a validator's EXIT transaction and the refund of its
+    /// bond.
+    pub exit_delay: usize,
+    /// Share of each fee a validator keeps before the rest is split with its
+    /// delegators, in percent.
+    pub commission_percent: u64,
+    /// Lowest fee a transaction can pay. Blocks with cheaper transactions
+    /// are invalid.
+    pub min_fee: Amount,
+    /// Most transactions a block can hold.
+    pub max_block_txns: usize,
+    /// Largest total size of a block's transactions, see
+    /// `Transaction::size`.
+    pub max_block_bytes: usize,
+    /// New coins credited to the validator of each block.
+    pub issuance: Issuance,
+}
+
+/// How the block reward changes over time.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Issuance {
+    /// The same reward for every block.
+    Fixed { reward: Amount },
+    /// `reward` for the first `interval` blocks, halved every `interval`
+    /// blocks after that.
+    Halving { reward: Amount, interval: usize },
+    /// `percent` of the total supply per `blocks_per_year` blocks, spread
+    /// evenly over the blocks.
+    Inflation { percent: u64, blocks_per_year: u64 },
+}
+
+impl Issuance {
+    /// Reward for the block at `height` given the total supply before it.
+    pub fn reward(&self, height: usize, supply: Amount) -> Amount {
+        match self {
+            Issuance::Fixed { reward } => *reward,
+            Issuance::Halving { reward, interval } => {
+                let halvings = height.checked_div(*interval).unwrap_or(0);
+                Amount::from_units(reward.units().checked_shr(halvings as u32).unwrap_or(0))
+            }
+            Issuance::Inflation {
+                percent,
+                blocks_per_year,
+            } => {
+                let per_year = supply.units() as u128 * *percent as u128 / 100;
+                let reward = per_year.checked_div(*blocks_per_year as u128).unwrap_or(0);
+                Amount::from_units(reward.min(u64::MAX as u128) as u64)
+            }
+        }
+    }
+}
+
+impl Default for ChainConfig {
+    fn default() -> Self {
+        Self {
+            unbonding_period: 20,
+            exit_delay: 10,
+            commission_percent: 10,
+            min_fee: Amount::from_coins(1),
+            max_block_txns: 1000,
+            max_block_bytes: 1_000_000,
+            issuance: Issuance::Fixed {
+                reward: Amount::from_coins(1),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn halves_the_reward_every_interval() {
+        let issuance = Issuance::Halving {
+            reward: Amount::from_coins(8),
+            interval: 10,
+        };
+        let reward = |height| issuance.reward(height, Amount::ZERO);
+        assert_eq!(reward(1), Amount::from_coins(8));
+        assert_eq!(reward(9), Amount::from_coins(8));
+        assert_eq!(reward(10), Amount::from_coins(4));
+        assert_eq!(reward(25), Amount::from_coins(2));
+        assert_eq!(reward(640), Amount::ZERO);
+
+        let never = Issuance::Halving {
+            reward: Amount::from_coins(8),
+            interval: 0,
+        };
+        assert_eq!(never.reward(1000, Amount::ZERO), Amount::from_coins(8));
+    }
+
+    #[test]
+    fn inflates_the_supply_per_block() {
+        let issuance = Issuance::Inflation {
+            percent: 5,
+            blocks_per_year: 1000,
+        };
+        assert_eq!(
+            issuance.reward(1, Amount::from_coins(1000)),
+            Amount::from_units(Amount::from_coins(1).units() / 20)
+        );
+        assert_eq!(
+            issuance.reward(1, Amount::from_coins(2000)),
+            Amount::from_units(Amount::from_coins(1).units() / 10)
+        );
+        assert_eq!(issuance.reward(1, Amount::ZERO), Amount::ZERO);
+
+        let never = Issuance::Inflation {
+            percent: 5,
+            blocks_per_year: 0,
+        };
+        assert_eq!(never.reward(1, Amount::from_coins(1000)), Amount::ZERO);
+    }
+}
@@ -0,0 +1,216 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::block::Block;
+use crate::encoding::Encoder;
+use crate::state::State;
+use crate::util::Util;
+use crate::wallet::Wallet;
+
+/// Validators vote on every block whose height is a multiple of this.
+pub const CHECKPOINT_INTERVAL: usize = 10;
+
+/// How far past the tip votes are kept, for checkpoints the node hasn't
+/// received yet.
+pub const MAX_VOTE_LEAD: usize = CHECKPOINT_INTERVAL;
+
+/// A validator's signed vote that the block `hash` at `height` is final.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Vote {
+    pub chain_id: String,
+    pub height: usize,
+    pub hash: String,
+    pub validator: String,
+    pub signature: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum VoteError {
+    WrongChain(String),
+    InvalidSignature,
+    NotValidator(String),
+    NotCheckpoint(usize),
+    AlreadyFinalized(usize),
+    TooFarAhead(usize),
+    DuplicateVote,
+}
+
+impl fmt::Display for VoteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VoteError::WrongChain(chain_id) => write!(f, "vote is for chain {}", chain_id),
+            VoteError::InvalidSignature => write!(f, "invalid vote signature"),
+            VoteError::NotValidator(address) => write!(f, "{} is not a validator", address),
+            VoteError::NotCheckpoint(height) => write!(f, "height {} is not a checkpoint", height),
+            VoteError::AlreadyFinalized(height) => {
+                write!(
+                    f,
+                    "height {} is at or below the finalized checkpoint",
+                    height
+                )
+            }
+            VoteError::TooFarAhead(height) => {
+                write!(f, "height {} is too far past the tip", height)
+            }
+            VoteError::DuplicateVote => write!(f, "validator already voted at this height"),
+        }
+    }
+}
+
+impl Vote {
+    pub fn new(chain_id: &str, height: usize, hash: String, mut wallet: Wallet) -> Self {
+        let signature = wallet.sign(&Vote::signing_hash(chain_id, height, &hash));
+        Self {
+            chain_id: chain_id.to_string(),
+            height,
+            hash,
+            validator: wallet.get_public_key(),
+            signature,
+        }
+    }
+
+    fn signing_hash(chain_id: &str, height: usize, hash: &str) -> String {
+        Util::hash_bytes(
+            &Encoder::new("pos/vote/v1")
+                .str(chain_id)
+                .u64(height as u64)
+                .str(hash)
+                .finish(),
+        )
+    }
+
+    pub fn verify(&self) -> bool {
+        matches!(
+            Util::verify_signature(
+                &self.validator,
+                &Vote::signing_hash(&self.chain_id, self.height, &self.hash),
+                &self.signature,
+            ),
+            Ok(true)
+        )
+    }
+}
+
+/// Collects checkpoint votes and tracks the latest finalized checkpoint. A
+/// checkpoint is final once validators holding at least 2/3 of the stake of
+/// the validator set voted for it.
+pub struct Finality {
+    pub height: usize,
+    pub hash: String,
+    // Height -> validator -> vote. A validator gets one vote per height.
+    votes: HashMap<usize, HashMap<String, Vote>>,
+}
+
+impl Finality {
+    pub fn new(genesis: &Block) -> Self {
+        Self {
+            height: 0,
+            hash: genesis.hash.clone(),
+            votes: HashMap::new(),
+        }
+    }
+
+    pub fn has_voted(&self, height: usize, validator: &str) -> bool {
+        self.votes
+            .get(&height)
+            .is_some_and(|votes| votes.contains_key(validator))
+    }
+
+    /// Keeps `vote` until its checkpoint can be finalized. `chain_len` is
+    /// the length of the node's chain, votes more than `MAX_VOTE_LEAD`
+    /// past it are rejected.
+    pub fn add_vote(
+        &mut self,
+        vote: Vote,
+        chain_len: usize,
+        state: &State,
+    ) -> Result<(), VoteError> {
+        if vote.chain_id != state.chain_id {
+            return Err(VoteError::WrongChain(vote.chain_id));
+        } else if vote.height == 0 || !vote.height.is_multiple_of(CHECKPOINT_INTERVAL) {
+            return Err(VoteError::NotCheckpoint(vote.height));
+        } else if vote.height <= self.height {
+            return Err(VoteError::AlreadyFinalized(vote.height));
+        } else if vote.height > chain_len + MAX_VOTE_LEAD {
+            return Err(VoteError::TooFarAhead(vote.height));
+        } else if !state.validators.is_active(&vote.validator) {
+            return Err(VoteError::NotValidator(vote.validator));
+        } else if self.has_voted(vote.height, &vote.validator) {
+            return Err(VoteError::DuplicateVote);
+        } else if !vote.verify() {
+            return Err(VoteError::InvalidSignature);
+        }
+
+        self.votes
+            .entry(vote.height)
+            .or_default()
+            .insert(vote.validator.clone(), vote);
+        Ok(())
+    }
+
+    /// Finalizes the highest checkpoint on `chain` with enough votes, with
+    /// stake weights taken from `state`. Returns true if it moved.
+    pub fn update(&mut self, chain: &[Block], state: &State) -> bool {
+        let validators: HashSet<&String> = state.validators.accounts.iter().collect();
+        let stake = |address: &String| state.stakes.staking_balance(address).units() as u128;
+        let total: u128 = validators.iter().map(|address| stake(address)).sum();
+
+        let finalized = self
+            .votes
+            .iter()
+            .filter(|(height, _)| **height < chain.len())
+            .filter_map(|(height, votes)| {
+                let hash = &chain[*height].hash;
+                let voted: u128 = votes
+                    .values()
+                    .filter(|vote| vote.hash == *hash && validators.contains(&vote.validator))
+                    .map(|vote| stake(&vote.validator))
+                    .sum();
+                (total > 0 && voted * 3 >= total * 2).then_some(*height)
+            })
+            .max();
+
+        match finalized {
+            Some(height) if height > self.height => {
+                self.height = height;
+                self.hash = chain[height].hash.clone();
+                self.votes.retain(|h, _| *h > height);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::genesis::Genesis;
+
+    const KEY: &str = "27a23bf39574e86464f4e638241b3ef3dd223d9a30bd97810ff29c992e747e5a230681c76f00b412ccf7757a8449c448a04acd735e497a7612b66d8bfcb8e576";
+
+    #[test]
+    fn rejects_votes_too_far_past_the_tip() {
+        let genesis = Genesis::default();
+        let state = State::new(&genesis);
+        let mut finality = Finality::new(&Block::genesis(&genesis));
+        let vote = |height| {
+            Vote::new(
+                &genesis.chain_id,
+                height,
+                String::new(),
+                Wallet::get_wallet(KEY.to_string()),
+            )
+        };
+
+        let far = MAX_VOTE_LEAD + 2 * CHECKPOINT_INTERVAL;
+        assert_eq!(
+            finality.add_vote(vote(far), 1, &state),
+            Err(VoteError::TooFarAhead(far))
+        );
+        assert!(!finality.has_voted(far, &vote(far).validator));
+        assert_eq!(finality.add_vote(vote(MAX_VOTE_LEAD), 1, &state), Ok(()));
+    }
+}
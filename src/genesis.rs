@@ -0,0 +1,164 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::amount::Amount;
+use crate::config::{ChainConfig, Issuance};
+use crate::encoding::Encoder;
+use crate::supply;
+use crate::util::Util;
+
+/// Everything a network starts from: the initial accounts, stakes and
+/// validators, the difficulty of the first blocks and the consensus
+/// parameters. Nodes only sync with peers that started from the same
+/// genesis.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Genesis {
+    pub chain_id: String,
+    pub timestamp: i64,
+    pub difficulty: u32,
+    pub balances: BTreeMap<String, Amount>,
+    pub stakes: BTreeMap<String, Amount>,
+    pub validators: Vec<String>,
+    #[serde(default)]
+    pub config: ChainConfig,
+}
+
+#[derive(Debug)]
+pub enum GenesisError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    EmptyChainId,
+    ZeroDifficulty,
+    NoValidators,
+    InvalidAddress(String),
+    DuplicateValidator(String),
+    Overflow,
+}
+
+impl fmt::Display for GenesisError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GenesisError::Io(err) => write!(f, "io error: {}", err),
+            GenesisError::Json(err) => write!(f, "malformed genesis file: {}", err),
+            GenesisError::EmptyChainId => write!(f, "chain id is empty"),
+            GenesisError::ZeroDifficulty => write!(f, "difficulty must be at least 1"),
+            GenesisError::NoValidators => write!(f, "genesis has no validators"),
+            GenesisError::InvalidAddress(address) => {
+                write!(f, "{} is not a valid public key", address)
+            }
+            GenesisError::DuplicateValidator(address) => {
+                write!(f, "validator {} is listed twice", address)
+            }
+            GenesisError::Overflow => write!(f, "initial supply overflows"),
+        }
+    }
+}
+
+impl From<std::io::Error> for GenesisError {
+    fn from(err: std::io::Error) -> Self {
+        GenesisError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for GenesisError {
+    fn from(err: serde_json::Error) -> Self {
+        GenesisError::Json(err)
+    }
+}
+
+impl Default for Genesis {
+    /// The development network: two validators with 500 coins each.
+    fn default() -> Self {
+        let first =
+            String::from("230681c76f00b412ccf7757a8449c448a04acd735e497a7612b66d8bfcb8e576");
+        let second =
+            String::from("5aede624154386ca358af195e13a46981b917ee8279f30a67d7a211a3d3e7243");
+        Self {
+            chain_id: String::from("pos-devnet"),
+            timestamp: 1650205976,
+            difficulty: 5,
+            balances: BTreeMap::from([
+                (first.clone(), Amount::from_coins(500)),
+                (second.clone(), Amount::from_coins(500)),
+            ]),
+            stakes: BTreeMap::from([
+                (first.clone(), Amount::from_coins(2)),
+                (second.clone(), Amount::from_coins(1)),
+            ]),
+            validators: vec![first, second],
+            config: ChainConfig::default(),
+        }
+    }
+}
+
+impl Genesis {
+    /// Reads and validates a JSON genesis file.
+    pub fn load(path: &Path) -> Result<Self, GenesisError> {
+        let genesis: Genesis = serde_json::from_slice(&fs::read(path)?)?;
+        genesis.validate()?;
+        Ok(genesis)
+    }
+
+    pub fn validate(&self) -> Result<(), GenesisError> {
+        if self.chain_id.is_empty() {
+            return Err(GenesisError::EmptyChainId);
+        } else if self.difficulty == 0 {
+            return Err(GenesisError::ZeroDifficulty);
+        } else if self.validators.is_empty() {
+            return Err(GenesisError::NoValidators);
+        }
+
+        if let Some(address) = self
+            .balances
+            .keys()
+            .chain(self.stakes.keys())
+            .chain(self.validators.iter())
+            .find(|address| !Util::is_valid_public_key(address))
+        {
+            return Err(GenesisError::InvalidAddress(address.clone()));
+        }
+
+        let mut seen = HashSet::new();
+        if let Some(address) = self
+            .validators
+            .iter()
+            .find(|address| !seen.insert(*address))
+        {
+            return Err(GenesisError::DuplicateValidator(address.clone()));
+        }
+
+        supply::sum(self.balances.values().chain(self.stakes.values()))
+            .ok_or(GenesisError::Overflow)?;
+        Ok(())
+    }
+
+    /// Hash of the chain id and consensus parameters, used as the genesis
+    /// block's `previous_hash`. The initial state is committed to by the
+    /// genesis block's `state_root`.
+    pub fn hash(&self) -> String {
+        let mut encoder = Encoder::new("pos/genesis/v1");
+        encoder
+            .str(&self.chain_id)
+            .u64(self.config.unbonding_period as u64)
+            .u64(self.config.exit_delay as u64)
+            .u64(self.config.commission_percent)
+            .u64(self.config.min_fee.units())
+            .u64(self.config.max_block_txns as u64)
+            .u64(self.config.max_block_bytes as u64);
+        match &self.config.issuance {
+            Issuance::Fixed { reward } => encoder.u8(0).u64(reward.units()),
+            Issuance::Halving { reward, interval } => {
+                encoder.u8(1).u64(reward.units()).u64(*interval as u64)
+            }
+            Issuance::Inflation {
+                percent,
+                blocks_per_year,
+            } => encoder.u8(2).u64(*percent).u64(*blocks_per_year),
+        };
+        Util::hash_bytes(&encoder.finish())
+    }
+}
@@ -8,7 +8,7 @@ use libp2p::{
     Transport,
 };
 use log::{error, info, warn};
-use std::time::Duration;
+use std::{env, path::Path, time::Duration};
 use tokio::{
     io::{stdin, AsyncBufReadExt, BufReader},
     select, spawn,
@@ -17,11 +17,23 @@ use tokio::{
 };
 
 mod account;
+mod amount;
 mod block;
+mod block_template;
+mod block_tree;
 mod blockchain;
+mod config;
+mod encoding;
+mod finality;
+mod genesis;
 mod mempool;
+mod merkle;
 mod p2p;
+mod slashing;
 mod stake;
+mod state;
+mod store;
+mod supply;
 mod transaction;
 mod util;
 mod validator;
@@ -29,6 +41,8 @@ mod wallet;
 
 use blockchain::Blockchain;
 
+use crate::genesis::Genesis;
+use crate::store::DiskStore;
 use crate::wallet::Wallet;
 
 #[tokio::main]
@@ -53,12 +67,23 @@ async fn main() {
     let wallet = Wallet::new();
     // let wallet = Wallet::get_wallet("5ae5066dd048ffb8f8628c44324e63c7b8782a026009a85a96935acb4921abbc5aede624154386ca358af195e13a46981b917ee8279f30a67d7a211a3d3e7243".to_string());
     // let wallet = Wallet::get_wallet("27a23bf39574e86464f4e638241b3ef3dd223d9a30bd97810ff29c992e747e5a230681c76f00b412ccf7757a8449c448a04acd735e497a7612b66d8bfcb8e576".to_string());
-    let behaviour = p2p::AppBehaviour::new(
-        Blockchain::new(wallet),
-        response_sender,
-        init_sender.clone(),
-    )
-    .await;
+    let genesis = match env::var("GENESIS_FILE") {
+        Ok(path) => {
+            info!("Using genesis file {}", path);
+            Genesis::load(Path::new(&path)).expect("can load genesis file")
+        }
+        Err(_) => Genesis::default(),
+    };
+    let blockchain = match env::var("DATA_DIR") {
+        Ok(dir) => {
+            info!("Using chain store at {}", dir);
+            let store = DiskStore::open(Path::new(&dir)).expect("can open chain store");
+            Blockchain::with_store(wallet, genesis, Box::new(store))
+                .expect("can load chain from store")
+        }
+        Err(_) => Blockchain::new(wallet, genesis),
+    };
+    let behaviour = p2p::AppBehaviour::new(blockchain, response_sender, init_sender.clone()).await;
 
     let mut swarm = SwarmBuilder::new(transp, behaviour, *p2p::PEER_ID)
         .executor(Box::new(|fut| {
@@ -116,6 +141,7 @@ async fn main() {
                     info!("connected nodes: {}", peers.len());
                     if !peers.is_empty() {
                         let req = p2p::ChainRequest {
+                            chain_id: swarm.behaviour().blockchain.genesis.chain_id.clone(),
                             from_peer_id: peers
                                 .iter()
                                 .last()
@@ -124,10 +150,11 @@ async fn main() {
                         };
 
                         let json = serde_json::to_string(&req).expect("can jsonify request");
+                        let topic = swarm.behaviour().topics.chain.clone();
                         swarm
                             .behaviour_mut()
                             .floodsub
-                            .publish(p2p::CHAIN_TOPIC.clone(), json.as_bytes());
+                            .publish(topic, json.as_bytes());
                     }
                 }
                 p2p::EventType::Mining => {
@@ -138,10 +165,12 @@ async fn main() {
                             .add_new_block(block.clone());
                         info!("broadcasting new block");
                         let json = serde_json::to_string(&block).expect("can jsonify request");
+                        let topic = swarm.behaviour().topics.block.clone();
                         swarm
                             .behaviour_mut()
                             .floodsub
-                            .publish(p2p::BLOCK_TOPIC.clone(), json.as_bytes());
+                            .publish(topic, json.as_bytes());
+                        swarm.behaviour_mut().publish_checkpoint_vote();
                     };
                 }
                 p2p::EventType::Input(line) => match line.as_str() {
@@ -153,6 +182,11 @@ async fn main() {
                     "ls validator" => p2p::handle_print_validator(&swarm),
                     "ls stakes" => p2p::handle_print_stake(&swarm),
                     "ls mempool" => p2p::handle_print_mempool(&swarm),
+                    "ls nonce" => p2p::handle_print_nonce(&mut swarm),
+                    "ls finalized" => p2p::handle_print_finalized(&swarm),
+                    "ls supply" => p2p::handle_print_supply(&swarm),
+                    cmd if cmd.starts_with("ls block") => p2p::handle_print_block(cmd, &swarm),
+                    cmd if cmd.starts_with("prove txn") => p2p::handle_prove_txn(cmd, &swarm),
                     cmd if cmd.starts_with("set wallet") => p2p::handle_set_wallet(cmd, &mut swarm),
                     cmd if cmd.starts_with("create txn") => p2p::handle_create_txn(cmd, &mut swarm),
                     _ => error!("unknown command"),
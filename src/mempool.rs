@@ -1,27 +1,348 @@
-use log::warn;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 
+use uuid::Uuid;
+
+use crate::amount::Amount;
+use crate::blockchain::MAX_CLOCK_DRIFT_SECONDS;
+use crate::state::TransactionError;
 use crate::transaction::Transaction;
 
+/// Lowest fee a transaction needs to be admitted to the pool and relayed.
+pub const MIN_RELAY_FEE: Amount = Amount::from_coins(1);
+
+/// Why a transaction was not admitted to the pool.
+#[derive(Debug, PartialEq)]
+pub enum MempoolError {
+    Duplicate,
+    FeeTooLow { minimum: Amount, fee: Amount },
+    Expired,
+    FutureTimestamp(i64),
+    SenderLimit(String),
+    Full,
+    Invalid(TransactionError),
+}
+
+impl fmt::Display for MempoolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MempoolError::Duplicate => write!(f, "transaction is already in the pool"),
+            MempoolError::FeeTooLow { minimum, fee } => {
+                write!(f, "fee {} is below the minimum {}", fee, minimum)
+            }
+            MempoolError::Expired => write!(f, "transaction is too old"),
+            MempoolError::FutureTimestamp(timestamp) => {
+                write!(f, "timestamp {} is in the future", timestamp)
+            }
+            MempoolError::SenderLimit(address) => {
+                write!(f, "{} has too many pending transactions", address)
+            }
+            MempoolError::Full => write!(f, "pool is full of transactions paying more"),
+            MempoolError::Invalid(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<TransactionError> for MempoolError {
+    fn from(err: TransactionError) -> Self {
+        MempoolError::Invalid(err)
+    }
+}
+
+/// Limits of the pool. They are local policy, nodes can differ.
+#[derive(Debug, Clone)]
+pub struct MempoolConfig {
+    pub max_count: usize,
+    /// Total size of the pooled transactions, see `Transaction::size`.
+    pub max_bytes: usize,
+    pub max_per_sender: usize,
+    /// Transactions are dropped this many seconds after their
+    /// `TransactionInput::timestamp`.
+    pub ttl_seconds: i64,
+}
+
+impl Default for MempoolConfig {
+    fn default() -> Self {
+        Self {
+            max_count: 5000,
+            max_bytes: 5_000_000,
+            max_per_sender: 64,
+            ttl_seconds: 3 * 60 * 60,
+        }
+    }
+}
+
+/// Pending transactions by id. When the pool is full a new transaction
+/// evicts the ones paying the lowest fee per byte, but only if it pays
+/// more. Only a sender's last pending transaction is evicted so the rest
+/// stay executable.
 pub struct Mempool {
-    pub transactions: Vec<Transaction>,
+    pub config: MempoolConfig,
+    transactions: HashMap<Uuid, Entry>,
+    // Sender -> nonce -> id of each pending transaction.
+    senders: HashMap<String, BTreeMap<u64, Uuid>>,
+    bytes: usize,
+}
+
+// A pooled transaction with its size, which is needed for every fee rate
+// comparison.
+struct Entry {
+    txn: Transaction,
+    size: usize,
+}
+
+impl Entry {
+    // Compares the fee per byte of two transactions.
+    fn fee_rate_cmp(&self, other: &Entry) -> Ordering {
+        let rate = self.txn.txn_output.fee.units() as u128 * other.size as u128;
+        let other_rate = other.txn.txn_output.fee.units() as u128 * self.size as u128;
+        rate.cmp(&other_rate)
+    }
 }
 
 impl Mempool {
     pub fn new() -> Self {
+        Mempool::with_config(MempoolConfig::default())
+    }
+
+    pub fn with_config(config: MempoolConfig) -> Self {
         Self {
-            transactions: vec![],
+            config,
+            transactions: HashMap::new(),
+            senders: HashMap::new(),
+            bytes: 0,
         }
     }
 
-    pub fn add_transaction(&mut self, txn: Transaction) {
-        self.transactions.push(txn);
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    pub fn contains(&self, id: &Uuid) -> bool {
+        self.transactions.contains_key(id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Transaction> {
+        self.transactions.values().map(|entry| &entry.txn)
+    }
+
+    /// Pending transactions, highest fee per byte first.
+    pub fn by_fee_rate(&self) -> Vec<&Transaction> {
+        let mut entries: Vec<&Entry> = self.transactions.values().collect();
+        entries.sort_by(|a, b| {
+            b.fee_rate_cmp(a)
+                .then_with(|| a.txn.txn_input.from.cmp(&b.txn.txn_input.from))
+                .then_with(|| a.txn.txn_input.nonce.cmp(&b.txn.txn_input.nonce))
+        });
+        entries.into_iter().map(|entry| &entry.txn).collect()
+    }
+
+    /// Pending transactions in the order a block should include them: the
+    /// highest fee per byte first, but each sender's in nonce order.
+    pub fn by_priority(&self) -> Vec<&Transaction> {
+        let mut senders: Vec<&String> = self.senders.keys().collect();
+        senders.sort();
+        // Each sender's pending transactions with the lowest nonce last.
+        let mut queues: Vec<Vec<&Entry>> = senders
+            .into_iter()
+            .map(|sender| self.entries_from(sender).into_iter().rev().collect())
+            .collect();
+
+        let mut ordered = Vec::with_capacity(self.len());
+        while let Some(next) = queues
+            .iter_mut()
+            .filter(|pending| !pending.is_empty())
+            .max_by(|a, b| a.last().unwrap().fee_rate_cmp(b.last().unwrap()))
+        {
+            ordered.push(&next.pop().unwrap().txn);
+        }
+        ordered
+    }
+
+    /// Addresses with pending transactions.
+    pub fn senders(&self) -> impl Iterator<Item = &String> {
+        self.senders.keys()
+    }
+
+    /// Pending transactions of `address` in nonce order.
+    pub fn pending_from(&self, address: &String) -> Vec<&Transaction> {
+        self.entries_from(address)
+            .into_iter()
+            .map(|entry| &entry.txn)
+            .collect()
+    }
+
+    fn entries_from(&self, address: &String) -> Vec<&Entry> {
+        self.senders.get(address).map_or(vec![], |nonces| {
+            nonces
+                .values()
+                .filter_map(|id| self.transactions.get(id))
+                .collect()
+        })
+    }
+
+    /// Next nonce for `address` after its pending transactions, if it has
+    /// any in the pool.
+    pub fn next_nonce(&self, address: &String) -> Option<u64> {
+        self.senders
+            .get(address)
+            .and_then(|nonces| nonces.keys().next_back())
+            .map(|nonce| nonce + 1)
+    }
+
+    /// Adds `txn`, evicting cheaper transactions if the pool is full. The
+    /// caller is expected to have validated it.
+    pub fn add_transaction(&mut self, txn: Transaction, now: i64) -> Result<(), MempoolError> {
+        if self.contains(&txn.id) {
+            return Err(MempoolError::Duplicate);
+        } else if self.is_expired(&txn, now) {
+            return Err(MempoolError::Expired);
+        } else if txn.txn_input.timestamp > now.saturating_add(MAX_CLOCK_DRIFT_SECONDS) {
+            return Err(MempoolError::FutureTimestamp(txn.txn_input.timestamp));
+        }
+        let from = &txn.txn_input.from;
+        if self.senders.get(from).map_or(0, |nonces| nonces.len()) >= self.config.max_per_sender {
+            return Err(MempoolError::SenderLimit(from.clone()));
+        }
+
+        let entry = Entry {
+            size: txn.size(),
+            txn,
+        };
+        let evicted = self.eviction_for(&entry)?;
+        evicted.iter().for_each(|id| {
+            self.remove(id);
+        });
+
+        self.bytes += entry.size;
+        self.senders
+            .entry(entry.txn.txn_input.from.clone())
+            .or_default()
+            .insert(entry.txn.txn_input.nonce, entry.txn.id);
+        self.transactions.insert(entry.txn.id, entry);
+        Ok(())
+    }
+
+    // Picks the transactions to drop to make room for `entry`: repeatedly
+    // the cheapest last transaction of another sender, as long as it pays a
+    // lower fee rate than `entry`.
+    fn eviction_for(&self, entry: &Entry) -> Result<Vec<Uuid>, MempoolError> {
+        let mut count = self.transactions.len() + 1;
+        let mut bytes = self.bytes + entry.size;
+        let mut tails: Vec<Vec<&Entry>> = self
+            .senders
+            .keys()
+            .filter(|sender| **sender != entry.txn.txn_input.from)
+            .map(|sender| self.entries_from(sender))
+            .collect();
+
+        let mut evicted = vec![];
+        while count > self.config.max_count || bytes > self.config.max_bytes {
+            let cheapest = tails
+                .iter_mut()
+                .filter(|pending| !pending.is_empty())
+                .min_by(|a, b| a.last().unwrap().fee_rate_cmp(b.last().unwrap()))
+                .ok_or(MempoolError::Full)?;
+            let victim = *cheapest.last().unwrap();
+            if victim.fee_rate_cmp(entry) != Ordering::Less {
+                return Err(MempoolError::Full);
+            }
+            cheapest.pop();
+            count -= 1;
+            bytes -= victim.size;
+            evicted.push(victim.txn.id);
+        }
+        Ok(evicted)
+    }
+
+    pub fn remove(&mut self, id: &Uuid) -> Option<Transaction> {
+        let Entry { txn, size } = self.transactions.remove(id)?;
+        self.bytes -= size;
+        if let Some(nonces) = self.senders.get_mut(&txn.txn_input.from) {
+            nonces.remove(&txn.txn_input.nonce);
+            if nonces.is_empty() {
+                self.senders.remove(&txn.txn_input.from);
+            }
+        }
+        Some(txn)
+    }
+
+    pub fn retain(&mut self, mut keep: impl FnMut(&Transaction) -> bool) {
+        let dropped: Vec<Uuid> = self
+            .iter()
+            .filter(|txn| !keep(txn))
+            .map(|txn| txn.id)
+            .collect();
+        dropped.iter().for_each(|id| {
+            self.remove(id);
+        });
+    }
+
+    /// Drops transactions older than the TTL.
+    pub fn expire(&mut self, now: i64) {
+        let ttl = self.config.ttl_seconds;
+        self.retain(|txn| txn.txn_input.timestamp.saturating_add(ttl) >= now);
+    }
+
+    fn is_expired(&self, txn: &Transaction, now: i64) -> bool {
+        txn.txn_input
+            .timestamp
+            .saturating_add(self.config.ttl_seconds)
+            < now
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::TransactionType;
+    use crate::wallet::Wallet;
+
+    const KEY: &str = "27a23bf39574e86464f4e638241b3ef3dd223d9a30bd97810ff29c992e747e5a230681c76f00b412ccf7757a8449c448a04acd735e497a7612b66d8bfcb8e576";
+    const TO: &str = "5aede624154386ca358af195e13a46981b917ee8279f30a67d7a211a3d3e7243";
+
+    fn txn(nonce: u64, timestamp: i64) -> Transaction {
+        let mut wallet = Wallet::get_wallet(KEY.to_string());
+        let mut txn = Transaction::new(
+            &mut wallet,
+            "pos-devnet",
+            TO.to_string(),
+            Amount::from_coins(1),
+            MIN_RELAY_FEE,
+            TransactionType::TRANSACTION,
+            nonce,
+        );
+        txn.txn_input.timestamp = timestamp;
+        txn
     }
 
-    pub fn transaction_exists(&mut self, txn: &Transaction) -> bool {
-        self.transactions.contains(txn)
+    #[test]
+    fn rejects_timestamps_outside_the_window() {
+        let now = 1650205976;
+        let mut mempool = Mempool::new();
+        assert_eq!(
+            mempool.add_transaction(txn(0, i64::MAX), now),
+            Err(MempoolError::FutureTimestamp(i64::MAX))
+        );
+        assert_eq!(
+            mempool.add_transaction(txn(0, i64::MIN), now),
+            Err(MempoolError::Expired)
+        );
+        assert_eq!(
+            mempool.add_transaction(txn(0, now + MAX_CLOCK_DRIFT_SECONDS), now),
+            Ok(())
+        );
     }
 
-    pub fn clear(&mut self) {
-        self.transactions.clear()
+    #[test]
+    fn expires_old_transactions() {
+        let now = 1650205976;
+        let mut mempool = Mempool::new();
+        mempool.add_transaction(txn(0, now), now).unwrap();
+        mempool.add_transaction(txn(1, now + 60), now + 60).unwrap();
+        mempool.expire(now + mempool.config.ttl_seconds + 1);
+        assert_eq!(mempool.len(), 1);
+        assert_eq!(mempool.next_nonce(&txn(0, now).txn_input.from), Some(2));
     }
 }
@@ -0,0 +1,122 @@
+use serde::{Deserialize, Serialize};
+
+use crate::encoding::Encoder;
+use crate::util::Util;
+
+/// One step of a Merkle proof: the sibling hash and which side it sits on.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ProofStep {
+    pub hash: String,
+    pub left: bool,
+}
+
+/// Path from a leaf up to the Merkle root.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MerkleProof {
+    pub index: usize,
+    pub siblings: Vec<ProofStep>,
+}
+
+fn hash_leaf(leaf: &str) -> String {
+    Util::hash_bytes(&Encoder::new("pos/merkle/leaf/v1").str(leaf).finish())
+}
+
+fn hash_node(left: &str, right: &str) -> String {
+    Util::hash_bytes(
+        &Encoder::new("pos/merkle/node/v1")
+            .str(left)
+            .str(right)
+            .finish(),
+    )
+}
+
+// An odd node at the end of a level is carried up unchanged rather than
+// paired with itself, so two different leaf lists never share a root.
+fn next_level(level: &[String]) -> Vec<String> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => hash_node(left, right),
+            [single] => single.clone(),
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+/// Merkle root over a list of hex hashes.
+pub fn merkle_root(leaves: &[String]) -> String {
+    if leaves.is_empty() {
+        return Util::hash_bytes(&Encoder::new("pos/merkle/empty/v1").finish());
+    }
+
+    let mut level: Vec<String> = leaves.iter().map(|leaf| hash_leaf(leaf)).collect();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level.remove(0)
+}
+
+pub fn prove(leaves: &[String], index: usize) -> Option<MerkleProof> {
+    if index >= leaves.len() {
+        return None;
+    }
+
+    let mut siblings = vec![];
+    let mut position = index;
+    let mut level: Vec<String> = leaves.iter().map(|leaf| hash_leaf(leaf)).collect();
+    while level.len() > 1 {
+        let sibling = position ^ 1;
+        if sibling < level.len() {
+            siblings.push(ProofStep {
+                hash: level[sibling].clone(),
+                left: sibling < position,
+            });
+        }
+        position /= 2;
+        level = next_level(&level);
+    }
+
+    Some(MerkleProof { index, siblings })
+}
+
+pub fn verify(leaf: &str, proof: &MerkleProof, root: &str) -> bool {
+    let computed = proof
+        .siblings
+        .iter()
+        .fold(hash_leaf(leaf), |hash, step| match step.left {
+            true => hash_node(&step.hash, &hash),
+            false => hash_node(&hash, &step.hash),
+        });
+    computed == root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TXN_HASH: &str = "093c32965f1611250ab79302d6c6b103b43ec79d7cd4efdf1b3f82c1fd5da9b8";
+
+    #[test]
+    fn matches_golden_vectors() {
+        assert_eq!(
+            merkle_root(&[]),
+            "9b19c8bbe81251cdb02226acfe47b6b231ca59929fa523f9b84c9afae4ecf4ca"
+        );
+        assert_eq!(
+            merkle_root(&[TXN_HASH.to_string()]),
+            "efd4ef6b0cfc337d4b8c86293c59a597679363cf23a1a7a27b54869999c12d10"
+        );
+    }
+
+    #[test]
+    fn proves_every_leaf() {
+        let leaves: Vec<String> = (0..5u8).map(|i| Util::hash_bytes(&[i])).collect();
+        let root = merkle_root(&leaves);
+        for (index, leaf) in leaves.iter().enumerate() {
+            let proof = prove(&leaves, index).unwrap();
+            assert!(verify(leaf, &proof, &root));
+            assert!(!verify(&leaves[(index + 1) % 5], &proof, &root));
+        }
+        assert_eq!(prove(&leaves, 5), None);
+    }
+}
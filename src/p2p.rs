@@ -1,6 +1,12 @@
 // use super::{App, Block};
 use crate::{
-    block::Block, blockchain::Blockchain, transaction, transaction::Transaction, wallet::Wallet,
+    amount::Amount,
+    block::{Block, BlockValidationError},
+    blockchain::Blockchain,
+    finality::Vote,
+    mempool::MempoolError,
+    transaction::Transaction,
+    wallet::Wallet,
 };
 
 use libp2p::{
@@ -13,17 +19,44 @@ use libp2p::{
 use log::{info, warn};
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use tokio::sync::mpsc;
+use uuid::Uuid;
 
 pub static KEYS: Lazy<identity::Keypair> = Lazy::new(identity::Keypair::generate_ed25519);
 pub static PEER_ID: Lazy<PeerId> = Lazy::new(|| PeerId::from(KEYS.public()));
-pub static CHAIN_TOPIC: Lazy<Topic> = Lazy::new(|| Topic::new("chains"));
-pub static BLOCK_TOPIC: Lazy<Topic> = Lazy::new(|| Topic::new("blocks"));
-pub static TXN_TOPIC: Lazy<Topic> = Lazy::new(|| Topic::new("transactions"));
+
+// Peers whose score drops to this value are dropped and ignored.
+const BAN_SCORE: i32 = -100;
+
+/// Gossip topics of one network. They are prefixed with the chain id so
+/// nodes of different networks on the same LAN don't hear each other.
+pub struct Topics {
+    pub chain: Topic,
+    pub block: Topic,
+    pub txn: Topic,
+    pub vote: Topic,
+}
+
+impl Topics {
+    pub fn new(chain_id: &str) -> Self {
+        let topic = |name: &str| Topic::new(format!("{}/{}", chain_id, name));
+        Self {
+            chain: topic("chains"),
+            block: topic("blocks"),
+            txn: topic("transactions"),
+            vote: topic("votes"),
+        }
+    }
+
+    pub fn contains(&self, topic: &Topic) -> bool {
+        [&self.chain, &self.block, &self.txn, &self.vote].contains(&topic)
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ChainResponse {
+    pub chain_id: String,
     pub blocks: Vec<Block>,
     pub txns: Vec<Transaction>,
     pub receiver: String,
@@ -31,6 +64,7 @@ pub struct ChainResponse {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ChainRequest {
+    pub chain_id: String,
     pub from_peer_id: String,
 }
 
@@ -50,6 +84,10 @@ pub struct AppBehaviour {
     pub init_sender: mpsc::UnboundedSender<bool>,
     #[behaviour(ignore)]
     pub blockchain: Blockchain,
+    #[behaviour(ignore)]
+    pub peer_scores: HashMap<PeerId, i32>,
+    #[behaviour(ignore)]
+    pub topics: Topics,
 }
 
 impl AppBehaviour {
@@ -59,6 +97,7 @@ impl AppBehaviour {
         init_sender: mpsc::UnboundedSender<bool>,
     ) -> Self {
         let mut behaviour = Self {
+            topics: Topics::new(&blockchain.genesis.chain_id),
             blockchain,
             floodsub: Floodsub::new(*PEER_ID),
             mdns: Mdns::new(Default::default())
@@ -66,32 +105,103 @@ impl AppBehaviour {
                 .expect("can create mdns"),
             response_sender,
             init_sender,
+            peer_scores: HashMap::new(),
         };
-        behaviour.floodsub.subscribe(CHAIN_TOPIC.clone());
-        behaviour.floodsub.subscribe(BLOCK_TOPIC.clone());
-        behaviour.floodsub.subscribe(TXN_TOPIC.clone());
+        behaviour.floodsub.subscribe(behaviour.topics.chain.clone());
+        behaviour.floodsub.subscribe(behaviour.topics.block.clone());
+        behaviour.floodsub.subscribe(behaviour.topics.txn.clone());
+        behaviour.floodsub.subscribe(behaviour.topics.vote.clone());
 
         behaviour
     }
+
+    /// Publishes our vote if the new tip is a checkpoint we should vote on.
+    pub fn publish_checkpoint_vote(&mut self) {
+        if let Some(vote) = self.blockchain.checkpoint_vote() {
+            info!(
+                "voting for checkpoint {} at height {}",
+                vote.hash, vote.height
+            );
+            let json = serde_json::to_string(&vote).expect("can jsonify vote");
+            self.floodsub
+                .publish(self.topics.vote.clone(), json.as_bytes());
+        }
+    }
+
+    // Messages from another network are ignored, they can reach us when
+    // nodes of several networks share a LAN.
+    fn is_other_chain(&self, chain_id: &str, peer: &PeerId) -> bool {
+        if chain_id != self.blockchain.genesis.chain_id {
+            info!("ignoring message for chain {} from {}", chain_id, peer);
+            return true;
+        }
+        false
+    }
+
+    fn is_banned(&self, peer: &PeerId) -> bool {
+        self.peer_scores
+            .get(peer)
+            .is_some_and(|score| *score <= BAN_SCORE)
+    }
+
+    fn penalize(&mut self, peer: &PeerId, err: &BlockValidationError) {
+        let score = self.peer_scores.entry(*peer).or_insert(0);
+        *score -= penalty(err);
+        warn!("peer {} score is now {}", peer, score);
+
+        if *score <= BAN_SCORE {
+            warn!("banning peer {}", peer);
+            self.floodsub.remove_node_from_partial_view(peer);
+        }
+    }
+}
+
+// Blocks that don't line up with our tip may come from an honest peer that
+// is on a fork or out of sync, anything else was built or altered wrongly.
+fn penalty(err: &BlockValidationError) -> i32 {
+    match err {
+        BlockValidationError::UnknownParent(_)
+        | BlockValidationError::ConflictsWithFinalized(_)
+        | BlockValidationError::WrongPreviousHash { .. }
+        | BlockValidationError::NonSequentialId { .. } => 5,
+        BlockValidationError::TimestampOutOfRange(_) => 20,
+        _ => 100,
+    }
 }
 
 // incoming event handler
 impl NetworkBehaviourEventProcess<FloodsubEvent> for AppBehaviour {
     fn inject_event(&mut self, event: FloodsubEvent) {
         if let FloodsubEvent::Message(msg) = event {
+            if self.is_banned(&msg.source)
+                || !msg.topics.iter().any(|topic| self.topics.contains(topic))
+            {
+                return;
+            }
+
             if let Ok(resp) = serde_json::from_slice::<ChainResponse>(&msg.data) {
-                if resp.receiver == PEER_ID.to_string() {
+                if resp.receiver == PEER_ID.to_string()
+                    && !self.is_other_chain(&resp.chain_id, &msg.source)
+                {
                     info!("Response from {}:", msg.source);
                     // resp.blocks.iter().for_each(|r| info!("{:?}", r));
 
-                    self.blockchain.replace_chain(&resp.blocks);
-                    self.blockchain.mempool.transactions = resp
-                        .txns
-                        .into_iter()
-                        .filter(|txn| Transaction::verify_txn(txn).is_ok())
-                        .collect();
+                    if let Err(e) = self.blockchain.replace_chain(&resp.blocks) {
+                        warn!("rejected chain from {}: {}", msg.source, e);
+                        self.penalize(&msg.source, &e);
+                    }
+                    for txn in resp.txns {
+                        let id = txn.id;
+                        match self.blockchain.admit_txn(txn) {
+                            Ok(()) | Err(MempoolError::Duplicate) => {}
+                            Err(e) => info!("leaving out synced transaction {}: {}", id, e),
+                        }
+                    }
                 }
             } else if let Ok(resp) = serde_json::from_slice::<ChainRequest>(&msg.data) {
+                if self.is_other_chain(&resp.chain_id, &msg.source) {
+                    return;
+                }
                 info!(
                     "sending local chain & mempool to {}",
                     msg.source.to_string()
@@ -100,32 +210,91 @@ impl NetworkBehaviourEventProcess<FloodsubEvent> for AppBehaviour {
 
                 if PEER_ID.to_string() == peer_id {
                     let json = serde_json::to_string(&ChainResponse {
+                        chain_id: self.blockchain.genesis.chain_id.clone(),
                         blocks: self.blockchain.chain.clone(),
-                        txns: self.blockchain.mempool.transactions.clone(),
+                        txns: self.blockchain.mempool.iter().cloned().collect(),
                         receiver: msg.source.to_string(),
                     })
                     .expect("can jsonify response");
 
-                    self.floodsub.publish(CHAIN_TOPIC.clone(), json.as_bytes());
+                    self.floodsub
+                        .publish(self.topics.chain.clone(), json.as_bytes());
+                }
+            } else if let Ok(vote) = serde_json::from_slice::<Vote>(&msg.data) {
+                if self.is_other_chain(&vote.chain_id, &msg.source) {
+                    return;
+                }
+                info!("received checkpoint vote from {}", msg.source);
+                match self.blockchain.add_vote(vote.clone()) {
+                    Ok(()) => {
+                        let json = serde_json::to_string(&vote).expect("can jsonify vote");
+                        self.floodsub
+                            .publish(self.topics.vote.clone(), json.as_bytes());
+                    }
+                    Err(e) => info!("ignoring vote at height {}: {}", vote.height, e),
                 }
             } else if let Ok(block) = serde_json::from_slice::<Block>(&msg.data) {
                 // info!("received new block from {}", msg.source.to_string());
                 info!("received new block {:?}", block);
-                if self.blockchain.chain.last().unwrap().id < block.id
-                    && self.blockchain.is_valid_block(block.clone())
+                if self.is_other_chain(&block.header.chain_id, &msg.source)
+                    || self.blockchain.tree.contains(&block.hash)
                 {
-                    info!("relaying new valid block");
-                    let json = serde_json::to_string(&block).expect("can jsonify request");
-                    self.floodsub.publish(BLOCK_TOPIC.clone(), json.as_bytes());
+                    return;
+                }
+                if let Some(txn) = self.blockchain.detect_equivocation(&block) {
+                    info!("broadcasting evidence against {}", block.header.validator);
+                    let json = serde_json::to_string(&txn).expect("can jsonify request");
+                    self.floodsub
+                        .publish(self.topics.txn.clone(), json.as_bytes());
+                }
+                match self.blockchain.add_block(block.clone()) {
+                    Ok(false) => info!("not relaying block {} on a side branch", block.hash),
+                    Ok(true) => {
+                        info!("relaying new valid block");
+                        let json = serde_json::to_string(&block).expect("can jsonify request");
+                        self.floodsub
+                            .publish(self.topics.block.clone(), json.as_bytes());
+                        self.publish_checkpoint_vote();
+                    }
+                    Err(BlockValidationError::UnknownParent(parent)) => {
+                        // We are missing part of the sender's branch, ask for its chain.
+                        info!(
+                            "unknown parent {}, requesting chain from {}",
+                            parent, msg.source
+                        );
+                        let json = serde_json::to_string(&ChainRequest {
+                            chain_id: self.blockchain.genesis.chain_id.clone(),
+                            from_peer_id: msg.source.to_string(),
+                        })
+                        .expect("can jsonify request");
+                        self.floodsub
+                            .publish(self.topics.chain.clone(), json.as_bytes());
+                    }
+                    Err(e) => {
+                        warn!(
+                            "rejected block {} from {}: {}",
+                            block.header.id, msg.source, e
+                        );
+                        self.penalize(&msg.source, &e);
+                    }
                 }
             } else if let Ok(txn) = serde_json::from_slice::<Transaction>(&msg.data) {
                 info!("received new transaction from {}", msg.source.to_string());
 
-                if !self.blockchain.txn_exist(&txn) && Transaction::verify_txn(&txn).is_ok() {
-                    info!("relaying new valid transaction");
-                    let json = serde_json::to_string(&txn).expect("can jsonify request");
-                    self.floodsub.publish(TXN_TOPIC.clone(), json.as_bytes());
-                    self.blockchain.add_txn(txn);
+                if self.is_other_chain(&txn.chain_id, &msg.source) {
+                    return;
+                }
+                // Only transactions we accepted are relayed.
+                let json = serde_json::to_string(&txn).expect("can jsonify request");
+                let id = txn.id;
+                match self.blockchain.admit_txn(txn) {
+                    Ok(()) => {
+                        info!("relaying new valid transaction");
+                        self.floodsub
+                            .publish(self.topics.txn.clone(), json.as_bytes());
+                    }
+                    Err(MempoolError::Duplicate) => {}
+                    Err(e) => warn!("dropping transaction {}: {}", id, e),
                 }
             }
         }
@@ -173,25 +342,76 @@ pub fn handle_print_chain(swarm: &Swarm<AppBehaviour>) {
     info!("{}", pretty_json);
 }
 
+pub fn handle_print_block(cmd: &str, swarm: &Swarm<AppBehaviour>) {
+    if let Some(data) = cmd.strip_prefix("ls block") {
+        let key = data.trim();
+        match swarm.behaviour().blockchain.get_block(key) {
+            Ok(Some(block)) => {
+                let pretty_json = serde_json::to_string_pretty(&block).expect("can jsonify blocks");
+                info!("{}", pretty_json);
+            }
+            Ok(None) => warn!("No block found for {}", key),
+            Err(e) => warn!("Failed to read block {}: {}", key, e),
+        }
+    }
+}
+
+pub fn handle_prove_txn(cmd: &str, swarm: &Swarm<AppBehaviour>) {
+    if let Some(data) = cmd.strip_prefix("prove txn") {
+        let id = match Uuid::parse_str(data.trim()) {
+            Ok(id) => id,
+            Err(e) => {
+                warn!("Invalid transaction id: {}", e);
+                return;
+            }
+        };
+
+        match swarm.behaviour().blockchain.get_txn_proof(&id) {
+            Some((header, txn, proof)) => {
+                let pretty_json = serde_json::to_string_pretty(&serde_json::json!({
+                    "header": header,
+                    "txn_hash": txn.hash(),
+                    "proof": proof,
+                }))
+                .expect("can jsonify proof");
+                info!("{}", pretty_json);
+                info!(
+                    "Proof valid: {}",
+                    header.verify_txn_inclusion(&txn.hash(), &proof)
+                );
+            }
+            None => warn!("Transaction {} not found on chain", id),
+        }
+    }
+}
+
 pub fn handle_print_balance(swarm: &Swarm<AppBehaviour>) {
     info!("Account Balance:");
-    let pretty_json = serde_json::to_string_pretty(&swarm.behaviour().blockchain.accounts.balances)
-        .expect("can jsonify blocks");
+    let pretty_json =
+        serde_json::to_string_pretty(&swarm.behaviour().blockchain.state.accounts.balances)
+            .expect("can jsonify blocks");
     info!("{}", pretty_json);
 }
 
 pub fn handle_print_validator(swarm: &Swarm<AppBehaviour>) {
     info!("Validators: ");
     let pretty_json =
-        serde_json::to_string_pretty(&swarm.behaviour().blockchain.validators.accounts)
+        serde_json::to_string_pretty(&swarm.behaviour().blockchain.state.validators.accounts)
             .expect("can jsonify blocks");
     info!("{}", pretty_json);
 }
 
 pub fn handle_print_stake(swarm: &Swarm<AppBehaviour>) {
     info!("Validators Stake: ");
-    let pretty_json = serde_json::to_string_pretty(&swarm.behaviour().blockchain.stakes.balances)
-        .expect("can jsonify blocks");
+    let stakes = &swarm.behaviour().blockchain.state.stakes;
+    let pretty_json = serde_json::to_string_pretty(&stakes.balances).expect("can jsonify blocks");
+    info!("{}", pretty_json);
+    info!("Delegations: ");
+    let pretty_json =
+        serde_json::to_string_pretty(&stakes.delegations).expect("can jsonify blocks");
+    info!("{}", pretty_json);
+    info!("Unbonding: ");
+    let pretty_json = serde_json::to_string_pretty(&stakes.unbonding).expect("can jsonify blocks");
     info!("{}", pretty_json);
 }
 
@@ -209,9 +429,32 @@ pub fn handle_print_wallet(swarm: &mut Swarm<AppBehaviour>) {
     info!("Node wallet public key: {}", pub_key);
 }
 
+pub fn handle_print_nonce(swarm: &mut Swarm<AppBehaviour>) {
+    let blockchain = &mut swarm.behaviour_mut().blockchain;
+    let pub_key = blockchain.wallet.get_public_key();
+    info!("Next nonce: {}", blockchain.get_next_nonce(&pub_key));
+}
+
+pub fn handle_print_finalized(swarm: &Swarm<AppBehaviour>) {
+    let finality = &swarm.behaviour().blockchain.finality;
+    info!(
+        "Finalized block {} at height {}",
+        finality.hash, finality.height
+    );
+}
+
+pub fn handle_print_supply(swarm: &Swarm<AppBehaviour>) {
+    let supply = swarm.behaviour().blockchain.state.supply();
+    let pretty_json = serde_json::to_string_pretty(&supply).expect("can jsonify supply");
+    info!("{}", pretty_json);
+    if let Err(e) = supply.check() {
+        warn!("{}", e);
+    }
+}
+
 pub fn handle_print_mempool(swarm: &Swarm<AppBehaviour>) {
     let pretty_json =
-        serde_json::to_string_pretty(&swarm.behaviour().blockchain.mempool.transactions)
+        serde_json::to_string_pretty(&swarm.behaviour().blockchain.mempool.by_fee_rate())
             .expect("can jsonify blocks");
     info!("{}", pretty_json);
 }
@@ -221,46 +464,56 @@ pub fn handle_create_txn(cmd: &str, swarm: &mut Swarm<AppBehaviour>) {
         let arg: Vec<&str> = data.split_whitespace().collect();
 
         let to = arg.get(0).expect("No receipient found").to_string();
-        let amount = arg
-            .get(1)
-            .expect("No amount found")
-            .to_string()
-            .parse::<f64>()
-            .expect("Convert amount string to float");
+        let amount = match arg.get(1).expect("No amount found").parse::<Amount>() {
+            Ok(amount) => amount,
+            Err(e) => {
+                warn!("Invalid amount: {}", e);
+                return;
+            }
+        };
         let category = arg.get(2).expect("No txntype found").to_string();
 
         let txn_type = match category.as_str() {
             "txn" => crate::transaction::TransactionType::TRANSACTION,
             "stake" => crate::transaction::TransactionType::STAKE,
             "validator" => crate::transaction::TransactionType::VALIDATOR,
+            "unstake" => crate::transaction::TransactionType::UNSTAKE,
+            "exit" => crate::transaction::TransactionType::EXIT,
+            "delegate" => crate::transaction::TransactionType::DELEGATE,
+            "undelegate" => crate::transaction::TransactionType::UNDELEGATE,
             _ => crate::transaction::TransactionType::TRANSACTION,
         };
 
         let behaviour = swarm.behaviour_mut();
 
+        let fee = match arg.get(3).map(|fee| fee.parse::<Amount>()) {
+            Some(Ok(fee)) => fee,
+            Some(Err(e)) => {
+                warn!("Invalid fee: {}", e);
+                return;
+            }
+            None => behaviour.blockchain.min_fee(),
+        };
+
         let mut wallet = behaviour.blockchain.wallet.clone();
 
-        if amount + transaction::TRANSACTION_FEE
-            > *behaviour.blockchain.get_balance(&wallet.get_public_key())
-        {
-            warn!("Wallet has insufficient amount");
-            return;
-        }
+        let nonce = behaviour
+            .blockchain
+            .get_next_nonce(&wallet.get_public_key());
 
-        match Blockchain::create_txn(&mut wallet, to, amount, txn_type) {
-            Ok(txn) => {
-                let json = serde_json::to_string(&txn).expect("can jsonify request");
+        let txn = behaviour
+            .blockchain
+            .create_txn(&mut wallet, to, amount, fee, txn_type, nonce);
+        let json = serde_json::to_string(&txn).expect("can jsonify request");
 
-                info!("Adding new transaction to mempool");
-                behaviour.blockchain.mempool.add_transaction(txn);
-                info!("Broadcasting new transaction");
-                behaviour
-                    .floodsub
-                    .publish(TXN_TOPIC.clone(), json.as_bytes());
-            }
-            Err(_) => {
-                warn!("Failed to create transaction: Unable to serialized transactions into json");
-            }
-        };
+        info!("Adding new transaction to mempool");
+        if let Err(e) = behaviour.blockchain.admit_txn(txn) {
+            warn!("Invalid transaction: {}", e);
+            return;
+        }
+        info!("Broadcasting new transaction");
+        behaviour
+            .floodsub
+            .publish(behaviour.topics.txn.clone(), json.as_bytes());
     }
 }
@@ -0,0 +1,178 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::amount::Amount;
+use crate::block::{self, Block, BlockHeader};
+use crate::util::Util;
+
+/// Share of an equivocating validator's stake that is burned, in percent.
+pub const SLASH_PERCENT: u64 = 50;
+
+/// A block header together with the validator's signature over its hash.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SignedHeader {
+    pub header: BlockHeader,
+    pub signature: String,
+}
+
+/// Two different headers signed by the same validator for the same height.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Evidence {
+    pub first: SignedHeader,
+    pub second: SignedHeader,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum EvidenceError {
+    Missing,
+    WrongChain(String),
+    DifferentHeight,
+    DifferentValidator,
+    SameBlock,
+    InvalidSignature,
+    NotValidator(String),
+    AlreadySlashed,
+    BeforeRegistration(usize),
+}
+
+impl fmt::Display for EvidenceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EvidenceError::Missing => write!(f, "evidence transaction carries no evidence"),
+            EvidenceError::WrongChain(chain_id) => {
+                write!(f, "header is for chain {}", chain_id)
+            }
+            EvidenceError::DifferentHeight => write!(f, "headers are at different heights"),
+            EvidenceError::DifferentValidator => {
+                write!(f, "headers are signed by different validators")
+            }
+            EvidenceError::SameBlock => write!(f, "headers are the same block"),
+            EvidenceError::InvalidSignature => write!(f, "invalid header signature"),
+            EvidenceError::NotValidator(address) => {
+                write!(f, "{} is not an active validator", address)
+            }
+            EvidenceError::AlreadySlashed => write!(f, "equivocation was already slashed"),
+            EvidenceError::BeforeRegistration(height) => {
+                write!(f, "validator registered again at height {}", height)
+            }
+        }
+    }
+}
+
+impl SignedHeader {
+    pub fn new(block: &Block) -> Self {
+        Self {
+            header: block.header.clone(),
+            signature: block.signature.clone(),
+        }
+    }
+
+    pub fn hash(&self) -> String {
+        block::calculate_hash(&self.header)
+    }
+
+    fn verify(&self) -> bool {
+        matches!(
+            Util::verify_signature(&self.header.validator, &self.hash(), &self.signature),
+            Ok(true)
+        )
+    }
+}
+
+impl Evidence {
+    pub fn new(first: &Block, second: &Block) -> Self {
+        Self {
+            first: SignedHeader::new(first),
+            second: SignedHeader::new(second),
+        }
+    }
+
+    pub fn offender(&self) -> &String {
+        &self.first.header.validator
+    }
+
+    /// Height the offender signed both headers at.
+    pub fn height(&self) -> usize {
+        self.first.header.id
+    }
+
+    /// Checks that both headers are for `chain_id` and validly signed by one
+    /// validator for the same height and differ.
+    pub fn verify(&self, chain_id: &str) -> Result<(), EvidenceError> {
+        if let Some(header) = [&self.first.header, &self.second.header]
+            .into_iter()
+            .find(|header| header.chain_id != chain_id)
+        {
+            return Err(EvidenceError::WrongChain(header.chain_id.clone()));
+        } else if self.first.header.id != self.second.header.id {
+            return Err(EvidenceError::DifferentHeight);
+        } else if self.first.header.validator != self.second.header.validator {
+            return Err(EvidenceError::DifferentValidator);
+        } else if self.first.hash() == self.second.hash() {
+            return Err(EvidenceError::SameBlock);
+        } else if !self.first.verify() || !self.second.verify() {
+            return Err(EvidenceError::InvalidSignature);
+        }
+        Ok(())
+    }
+}
+
+/// Part of `stake` burned when its owner is slashed.
+pub fn slash_amount(stake: Amount) -> Amount {
+    Amount::from_units((stake.units() as u128 * SLASH_PERCENT as u128 / 100) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::genesis::Genesis;
+    use crate::wallet::Wallet;
+
+    const KEY: &str = "27a23bf39574e86464f4e638241b3ef3dd223d9a30bd97810ff29c992e747e5a230681c76f00b412ccf7757a8449c448a04acd735e497a7612b66d8bfcb8e576";
+
+    fn block(genesis: &Genesis, timestamp: i64) -> Block {
+        let previous = Block::genesis(genesis);
+        Block::new(
+            &previous,
+            timestamp,
+            vec![],
+            previous.header.state_root.clone(),
+            genesis.difficulty,
+            Wallet::get_wallet(KEY.to_string()),
+        )
+    }
+
+    #[test]
+    fn verifies_two_blocks_at_the_same_height() {
+        let genesis = Genesis::default();
+        let evidence = Evidence::new(&block(&genesis, 1), &block(&genesis, 2));
+        assert_eq!(evidence.verify(&genesis.chain_id), Ok(()));
+
+        let same = Evidence::new(&block(&genesis, 1), &block(&genesis, 1));
+        assert_eq!(
+            same.verify(&genesis.chain_id),
+            Err(EvidenceError::SameBlock)
+        );
+    }
+
+    #[test]
+    fn rejects_headers_from_another_chain() {
+        let genesis = Genesis::default();
+        let other = Genesis {
+            chain_id: String::from("pos-testnet"),
+            ..Genesis::default()
+        };
+        let evidence = Evidence::new(&block(&genesis, 1), &block(&other, 1));
+        assert_eq!(
+            evidence.verify(&genesis.chain_id),
+            Err(EvidenceError::WrongChain(other.chain_id.clone()))
+        );
+
+        let both = Evidence::new(&block(&other, 1), &block(&other, 2));
+        assert_eq!(
+            both.verify(&genesis.chain_id),
+            Err(EvidenceError::WrongChain(other.chain_id))
+        );
+    }
+}
@@ -1,43 +1,176 @@
+use crate::amount::{Amount, AmountError};
+use crate::slashing;
 use crate::transaction::Transaction;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+
+/// Unstaked funds waiting to be released back to their owner's account.
+/// They can still be slashed until then.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Unbonding {
+    pub address: String,
+    pub amount: Amount,
+    pub release_height: usize,
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Stake {
     pub accounts: Vec<String>,
-    pub balances: HashMap<String, u64>,
+    pub balances: HashMap<String, Amount>,
+    pub unbonding: Vec<Unbonding>,
+    /// Validator -> delegator -> delegated amount.
+    pub delegations: BTreeMap<String, BTreeMap<String, Amount>>,
 }
 
 impl Stake {
     pub fn new() -> Self {
         Self {
-            accounts: vec![
-                "230681c76f00b412ccf7757a8449c448a04acd735e497a7612b66d8bfcb8e576".to_string(),
-                "5aede624154386ca358af195e13a46981b917ee8279f30a67d7a211a3d3e7243".to_string(),
-            ],
-            balances: HashMap::from([
-                (
-                    "230681c76f00b412ccf7757a8449c448a04acd735e497a7612b66d8bfcb8e576".to_string(),
-                    2,
-                ),
-                (
-                    "5aede624154386ca358af195e13a46981b917ee8279f30a67d7a211a3d3e7243".to_string(),
-                    1,
-                ),
-            ]),
+            accounts: vec![],
+            balances: HashMap::new(),
+            unbonding: vec![],
+            delegations: BTreeMap::new(),
         }
     }
 
     pub fn initialize(&mut self, address: &String) {
         if !self.balances.contains_key(address) {
-            self.balances.insert(address.to_string(), 0);
+            self.balances.insert(address.to_string(), Amount::ZERO);
             self.accounts.push(address.to_string());
         }
     }
 
-    pub fn add_stake(&mut self, from: &String, amount: &u64) {
+    pub fn add_stake(&mut self, from: &String, amount: &Amount) -> Result<(), AmountError> {
+        self.initialize(from);
+        let balance = self.balances.get_mut(from).unwrap();
+        *balance = balance.checked_add(*amount).ok_or(AmountError::Overflow)?;
+        Ok(())
+    }
+
+    pub fn delegate(
+        &mut self,
+        validator: &String,
+        delegator: &String,
+        amount: &Amount,
+    ) -> Result<(), AmountError> {
+        let delegation = self
+            .delegations
+            .entry(validator.to_string())
+            .or_default()
+            .entry(delegator.to_string())
+            .or_default();
+        *delegation = delegation
+            .checked_add(*amount)
+            .ok_or(AmountError::Overflow)?;
+        Ok(())
+    }
+
+    /// Moves `amount` of `delegator`'s delegation to `validator` into the
+    /// unbonding queue.
+    pub fn undelegate(
+        &mut self,
+        validator: &String,
+        delegator: &String,
+        amount: &Amount,
+        release_height: usize,
+    ) -> Result<(), AmountError> {
+        let remaining = self
+            .delegation(validator, delegator)
+            .checked_sub(*amount)
+            .ok_or(AmountError::Underflow)?;
+        let delegators = self.delegations.get_mut(validator).unwrap();
+        if remaining == Amount::ZERO {
+            delegators.remove(delegator);
+            if delegators.is_empty() {
+                self.delegations.remove(validator);
+            }
+        } else {
+            delegators.insert(delegator.to_string(), remaining);
+        }
+        self.add_unbonding(delegator, *amount, release_height);
+        Ok(())
+    }
+
+    pub fn delegation(&self, validator: &String, delegator: &String) -> Amount {
+        self.delegations
+            .get(validator)
+            .and_then(|delegators| delegators.get(delegator))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Total delegated to `validator`.
+    pub fn delegated(&self, validator: &String) -> Amount {
+        self.delegations
+            .get(validator)
+            .map_or(Amount::ZERO, |delegators| {
+                delegators.values().fold(Amount::ZERO, |total, amount| {
+                    total.checked_add(*amount).unwrap_or(total)
+                })
+            })
+    }
+
+    /// Own stake plus delegations, the weight `address` has as a validator.
+    pub fn staking_balance(&self, address: &String) -> Amount {
+        let own = self.balances.get(address).copied().unwrap_or_default();
+        own.checked_add(self.delegated(address)).unwrap_or(own)
+    }
+
+    /// Queues `amount` to be paid to `address` at `release_height`.
+    pub fn add_unbonding(&mut self, address: &String, amount: Amount, release_height: usize) {
+        self.unbonding.push(Unbonding {
+            address: address.to_string(),
+            amount,
+            release_height,
+        });
+    }
+
+    /// Moves `amount` of `from`'s stake into the unbonding queue.
+    pub fn unstake(
+        &mut self,
+        from: &String,
+        amount: &Amount,
+        release_height: usize,
+    ) -> Result<(), AmountError> {
         self.initialize(from);
-        *self.balances.get_mut(from).unwrap() += amount;
+        let balance = self.balances.get_mut(from).unwrap();
+        *balance = balance.checked_sub(*amount).ok_or(AmountError::Underflow)?;
+        self.add_unbonding(from, *amount, release_height);
+        Ok(())
+    }
+
+    /// Removes and returns the entries due at `height`.
+    pub fn release(&mut self, height: usize) -> Vec<Unbonding> {
+        let (released, pending) = std::mem::take(&mut self.unbonding)
+            .into_iter()
+            .partition(|entry| entry.release_height <= height);
+        self.unbonding = pending;
+        released
+    }
+
+    /// Burns the slashed share of `address`'s stake, of its unbonding
+    /// entries and of the delegations to it and returns the total burned.
+    pub fn slash(&mut self, address: &String) -> Amount {
+        self.initialize(address);
+        let balance = self.balances.get_mut(address).unwrap();
+        let mut burned = slashing::slash_amount(*balance);
+        *balance = balance.checked_sub(burned).unwrap_or_default();
+
+        for entry in self.unbonding.iter_mut() {
+            if entry.address == *address {
+                let slashed = slashing::slash_amount(entry.amount);
+                entry.amount = entry.amount.checked_sub(slashed).unwrap_or_default();
+                burned = burned.checked_add(slashed).unwrap_or(burned);
+            }
+        }
+
+        if let Some(delegators) = self.delegations.get_mut(address) {
+            for amount in delegators.values_mut() {
+                let slashed = slashing::slash_amount(*amount);
+                *amount = amount.checked_sub(slashed).unwrap_or_default();
+                burned = burned.checked_add(slashed).unwrap_or(burned);
+            }
+        }
+        burned
     }
 
     pub fn get_max(&mut self, addresses: &Vec<String>) -> String {
@@ -53,12 +186,64 @@ impl Stake {
             .to_string()
     }
 
-    pub fn update(&mut self, txn: &Transaction) {
-        self.add_stake(&txn.txn_input.from, &(*&txn.txn_output.amount as u64))
+    pub fn update(&mut self, txn: &Transaction) -> Result<(), AmountError> {
+        self.add_stake(&txn.txn_input.from, &txn.txn_output.amount)
     }
+}
 
-    pub fn get_balance(&mut self, address: &String) -> &u64 {
-        self.initialize(address);
-        self.balances.get(address).unwrap()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::UNIT;
+
+    #[test]
+    fn counts_delegations_in_the_staking_balance() {
+        let validator = String::from("validator");
+        let (first, second) = (String::from("first"), String::from("second"));
+        let mut stake = Stake::new();
+        stake.add_stake(&validator, &Amount::from_coins(2)).unwrap();
+        stake
+            .delegate(&validator, &first, &Amount::from_coins(3))
+            .unwrap();
+        stake
+            .delegate(&validator, &second, &Amount::from_coins(1))
+            .unwrap();
+        assert_eq!(stake.delegated(&validator), Amount::from_coins(4));
+        assert_eq!(stake.staking_balance(&validator), Amount::from_coins(6));
+        assert_eq!(stake.staking_balance(&first), Amount::ZERO);
+
+        stake
+            .undelegate(&validator, &second, &Amount::from_coins(1), 5)
+            .unwrap();
+        assert_eq!(stake.delegation(&validator, &second), Amount::ZERO);
+        assert_eq!(stake.staking_balance(&validator), Amount::from_coins(5));
+        assert_eq!(stake.release(5)[0].amount, Amount::from_coins(1));
+    }
+
+    #[test]
+    fn slashes_stake_unbonding_and_delegations() {
+        let validator = String::from("validator");
+        let (delegator, other) = (String::from("delegator"), String::from("other"));
+        let mut stake = Stake::new();
+        stake.add_stake(&validator, &Amount::from_coins(3)).unwrap();
+        stake
+            .unstake(&validator, &Amount::from_coins(1), 5)
+            .unwrap();
+        stake
+            .delegate(&validator, &delegator, &Amount::from_coins(4))
+            .unwrap();
+        stake.add_unbonding(&other, Amount::from_coins(8), 5);
+
+        assert_eq!(
+            stake.slash(&validator),
+            Amount::from_units(3 * UNIT + UNIT / 2)
+        );
+        assert_eq!(stake.balances[&validator], Amount::from_coins(1));
+        assert_eq!(stake.unbonding[0].amount, Amount::from_units(UNIT / 2));
+        assert_eq!(
+            stake.delegation(&validator, &delegator),
+            Amount::from_coins(2)
+        );
+        assert_eq!(stake.unbonding[1].amount, Amount::from_coins(8));
     }
 }
@@ -0,0 +1,1318 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use crate::account::Account;
+use crate::amount::{Amount, AmountError};
+use crate::block::{Block, BlockValidationError};
+use crate::config::ChainConfig;
+use crate::encoding::Encoder;
+use crate::genesis::Genesis;
+use crate::merkle;
+use crate::slashing::EvidenceError;
+use crate::stake::{Stake, Unbonding};
+use crate::supply::{self, Supply};
+use crate::transaction::{Transaction, TransactionType};
+use crate::util::Util;
+use crate::validator::{Validator, VALIDATOR_BOND};
+
+/// Recipient used by STAKE, VALIDATOR, UNSTAKE and EVIDENCE transactions,
+/// which don't pay another address.
+pub const SYSTEM_ADDRESS: &str = "0";
+
+#[derive(Debug, PartialEq)]
+pub enum TransactionError {
+    WrongChain(String),
+    InvalidSignature,
+    InvalidNonce { expected: u64, actual: u64 },
+    FeeTooLow { minimum: Amount, fee: Amount },
+    InsufficientBalance { required: Amount, available: Amount },
+    InsufficientStake { required: Amount, available: Amount },
+    InvalidRecipient(String),
+    ZeroAmount,
+    BondTooLow(Amount),
+    InvalidEvidence(EvidenceError),
+    AlreadyValidator(String),
+    NotValidator(String),
+    Overflow,
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransactionError::InvalidSignature => write!(f, "invalid signature"),
+            TransactionError::InvalidNonce { expected, actual } => {
+                write!(f, "invalid nonce {}, expected {}", actual, expected)
+            }
+            TransactionError::FeeTooLow { minimum, fee } => {
+                write!(f, "fee {} is below the minimum {}", fee, minimum)
+            }
+            TransactionError::InsufficientBalance {
+                required,
+                available,
+            } => write!(
+                f,
+                "insufficient balance: requires {} but only {} available",
+                required, available
+            ),
+            TransactionError::InsufficientStake {
+                required,
+                available,
+            } => write!(
+                f,
+                "insufficient stake: unstaking {} but only {} staked",
+                required, available
+            ),
+            TransactionError::InvalidRecipient(to) => write!(f, "invalid recipient {}", to),
+            TransactionError::ZeroAmount => write!(f, "amount is zero"),
+            TransactionError::BondTooLow(amount) => write!(
+                f,
+                "validator bond {} is below the minimum of {}",
+                amount, VALIDATOR_BOND
+            ),
+            TransactionError::InvalidEvidence(e) => write!(f, "invalid evidence: {}", e),
+            TransactionError::AlreadyValidator(address) => {
+                write!(f, "{} is already a validator", address)
+            }
+            TransactionError::NotValidator(address) => {
+                write!(f, "{} is not a validator", address)
+            }
+            TransactionError::Overflow => write!(f, "amount overflow"),
+            TransactionError::WrongChain(chain_id) => {
+                write!(f, "transaction is for chain {}", chain_id)
+            }
+        }
+    }
+}
+
+impl From<AmountError> for TransactionError {
+    fn from(_: AmountError) -> Self {
+        TransactionError::Overflow
+    }
+}
+
+/// Values a block is about to overwrite, recorded before executing it so
+/// the block can be reverted without replaying the chain. Entries hold the
+/// previous value, `None` if the entry didn't exist yet.
+#[derive(Debug, Clone)]
+pub struct UndoJournal {
+    balances: Vec<(String, Option<Amount>)>,
+    nonces: Vec<(String, Option<u64>)>,
+    stakes: Vec<(String, Option<Amount>)>,
+    delegations: Vec<(String, Option<BTreeMap<String, Amount>>)>,
+    account_count: usize,
+    stake_count: usize,
+    unbonding: Vec<Unbonding>,
+    validators: Validator,
+    slashed_count: usize,
+    total_supply: Amount,
+    burned: Amount,
+    height: usize,
+}
+
+/// The part of the state a sender's transactions are checked against, as
+/// changed by its pending transactions. Lets the mempool check a sender's
+/// transactions one after another without copying the whole state.
+#[derive(Debug, Clone)]
+pub struct SenderState {
+    pub address: String,
+    pub balance: Amount,
+    pub nonce: u64,
+    pub stake: Amount,
+    pub validator: bool,
+    // Delegations changed by pending transactions, by validator. The rest
+    // are read from the state.
+    delegations: BTreeMap<String, Amount>,
+}
+
+impl SenderState {
+    fn delegation(&self, state: &State, validator: &String) -> Amount {
+        self.delegations
+            .get(validator)
+            .copied()
+            .unwrap_or_else(|| state.stakes.delegation(validator, &self.address))
+    }
+
+    // Applies a transaction that passed `State::check_txn` against `self`.
+    fn apply(&mut self, state: &State, txn: &Transaction) -> Result<(), TransactionError> {
+        let amount = txn.txn_output.amount;
+        let fee = txn.txn_output.fee;
+        let to = &txn.txn_output.to;
+        self.nonce += 1;
+        match txn.txn_type {
+            TransactionType::UNSTAKE => {
+                self.stake = self
+                    .stake
+                    .checked_sub(amount)
+                    .ok_or(TransactionError::Overflow)?;
+            }
+            TransactionType::UNDELEGATE => {
+                let delegated = self.delegation(state, to);
+                let delegated = delegated
+                    .checked_sub(amount)
+                    .ok_or(TransactionError::Overflow)?;
+                self.delegations.insert(to.clone(), delegated);
+            }
+            TransactionType::EXIT => self.validator = false,
+            TransactionType::STAKE => {
+                self.stake = self
+                    .stake
+                    .checked_add(amount)
+                    .ok_or(TransactionError::Overflow)?;
+            }
+            TransactionType::VALIDATOR => self.validator = true,
+            TransactionType::DELEGATE => {
+                let delegated = self.delegation(state, to);
+                let delegated = delegated
+                    .checked_add(amount)
+                    .ok_or(TransactionError::Overflow)?;
+                self.delegations.insert(to.clone(), delegated);
+            }
+            TransactionType::TRANSACTION | TransactionType::EVIDENCE => {}
+        }
+        let spent = match txn.txn_type {
+            TransactionType::UNSTAKE | TransactionType::UNDELEGATE | TransactionType::EXIT => fee,
+            _ => amount.checked_add(fee).ok_or(TransactionError::Overflow)?,
+        };
+        self.balance = self
+            .balance
+            .checked_sub(spent)
+            .ok_or(TransactionError::Overflow)?;
+        Ok(())
+    }
+}
+
+/// Account, stake and validator state produced by executing the chain.
+/// `height` is the block being executed, or the last executed one.
+/// `total_supply` is the total of all coins in existence and `burned` the
+/// total destroyed by slashing. `slashed` lists the validators slashed and
+/// the height of their equivocation, in the order they were slashed.
+#[derive(Debug, Clone)]
+pub struct State {
+    pub accounts: Account,
+    pub stakes: Stake,
+    pub validators: Validator,
+    pub slashed: Vec<(String, usize)>,
+    pub chain_id: String,
+    pub config: ChainConfig,
+    pub total_supply: Amount,
+    pub burned: Amount,
+    pub height: usize,
+}
+
+impl State {
+    /// The state before the first block: the accounts, stakes and
+    /// validators of `genesis`.
+    pub fn new(genesis: &Genesis) -> Self {
+        let mut state = Self {
+            accounts: Account::new(),
+            stakes: Stake::new(),
+            validators: Validator::new(),
+            slashed: vec![],
+            chain_id: genesis.chain_id.clone(),
+            config: genesis.config.clone(),
+            total_supply: Amount::ZERO,
+            burned: Amount::ZERO,
+            height: 0,
+        };
+        // Genesis::validate made sure the totals fit.
+        genesis.balances.iter().for_each(|(address, amount)| {
+            let _ = state.accounts.increment(address, amount);
+        });
+        genesis.stakes.iter().for_each(|(address, amount)| {
+            let _ = state.stakes.add_stake(address, amount);
+        });
+        state.validators.accounts = genesis.validators.clone();
+        state.total_supply = state.supply().held().unwrap_or_default();
+        state
+    }
+
+    /// Breakdown of the total supply by where the funds are held.
+    pub fn supply(&self) -> Supply {
+        let sum = |amounts: Vec<&Amount>| supply::sum(amounts.into_iter()).unwrap_or_default();
+        Supply {
+            total: self.total_supply,
+            circulating: sum(self.accounts.balances.values().collect()),
+            staked: sum(self.stakes.balances.values().collect()),
+            delegated: sum(self
+                .stakes
+                .delegations
+                .values()
+                .flat_map(|delegators| delegators.values())
+                .collect()),
+            bonded: sum(self.validators.bonds.values().collect()),
+            unbonding: sum(self
+                .stakes
+                .unbonding
+                .iter()
+                .map(|entry| &entry.amount)
+                .collect()),
+            burned: self.burned,
+        }
+    }
+
+    /// Commitment to the whole state: a Merkle root over the roots of the
+    /// account, stake, delegation, unbonding, validator and slashing trees. Accounts,
+    /// stakes and delegations are leaves sorted by address; empty entries
+    /// are left out since lookups create them as a side effect.
+    pub fn root(&self) -> String {
+        let mut addresses: Vec<&String> = self
+            .accounts
+            .balances
+            .keys()
+            .chain(self.accounts.nonces.keys())
+            .collect();
+        addresses.sort();
+        addresses.dedup();
+        let accounts: Vec<String> = addresses
+            .into_iter()
+            .map(|address| {
+                let balance = self
+                    .accounts
+                    .balances
+                    .get(address)
+                    .copied()
+                    .unwrap_or_default();
+                (address, balance, self.accounts.get_nonce(address))
+            })
+            .filter(|(_, balance, nonce)| *balance != Amount::ZERO || *nonce != 0)
+            .map(|(address, balance, nonce)| {
+                Util::hash_bytes(
+                    &Encoder::new("pos/state/account/v1")
+                        .str(address)
+                        .u64(balance.units())
+                        .u64(nonce)
+                        .finish(),
+                )
+            })
+            .collect();
+
+        let mut stakes: Vec<(&String, &Amount)> = self
+            .stakes
+            .balances
+            .iter()
+            .filter(|(_, amount)| **amount != Amount::ZERO)
+            .collect();
+        stakes.sort();
+        let stakes: Vec<String> = stakes
+            .into_iter()
+            .map(|(address, amount)| {
+                Util::hash_bytes(
+                    &Encoder::new("pos/state/stake/v1")
+                        .str(address)
+                        .u64(amount.units())
+                        .finish(),
+                )
+            })
+            .collect();
+
+        let delegations: Vec<String> = self
+            .stakes
+            .delegations
+            .iter()
+            .flat_map(|(validator, delegators)| {
+                delegators
+                    .iter()
+                    .map(move |(delegator, amount)| (validator, delegator, amount))
+            })
+            .filter(|(_, _, amount)| **amount != Amount::ZERO)
+            .map(|(validator, delegator, amount)| {
+                Util::hash_bytes(
+                    &Encoder::new("pos/state/delegation/v1")
+                        .str(validator)
+                        .str(delegator)
+                        .u64(amount.units())
+                        .finish(),
+                )
+            })
+            .collect();
+
+        // The unbonding queue, the validator list and the slashings keep
+        // their order.
+        let unbonding: Vec<String> = self
+            .stakes
+            .unbonding
+            .iter()
+            .map(|entry| {
+                Util::hash_bytes(
+                    &Encoder::new("pos/state/unbonding/v1")
+                        .str(&entry.address)
+                        .u64(entry.amount.units())
+                        .u64(entry.release_height as u64)
+                        .finish(),
+                )
+            })
+            .collect();
+
+        let validators: Vec<String> = self
+            .validators
+            .accounts
+            .iter()
+            .map(|address| {
+                let bond = self
+                    .validators
+                    .bonds
+                    .get(address)
+                    .copied()
+                    .unwrap_or_default();
+                Util::hash_bytes(
+                    &Encoder::new("pos/state/validator/v3")
+                        .str(address)
+                        .u64(bond.units())
+                        .u64(self.validators.registration_height(address) as u64)
+                        .finish(),
+                )
+            })
+            .collect();
+
+        let slashed: Vec<String> = self
+            .slashed
+            .iter()
+            .map(|(address, height)| {
+                Util::hash_bytes(
+                    &Encoder::new("pos/state/slashed/v1")
+                        .str(address)
+                        .u64(*height as u64)
+                        .finish(),
+                )
+            })
+            .collect();
+
+        merkle::merkle_root(&[
+            merkle::merkle_root(&accounts),
+            merkle::merkle_root(&stakes),
+            merkle::merkle_root(&delegations),
+            merkle::merkle_root(&unbonding),
+            merkle::merkle_root(&validators),
+            merkle::merkle_root(&slashed),
+        ])
+    }
+
+    /// Records everything executing `block` on this state can change: the
+    /// accounts and stakes of the validator, its delegators, every sender
+    /// and recipient and of unbonding entries due, the delegations to
+    /// recipients and slashed validators, the unbonding queue, the
+    /// validator set and the slashings.
+    pub fn journal(&self, block: &Block) -> UndoJournal {
+        let mut addresses = vec![&block.header.validator];
+        if let Some(delegators) = self.stakes.delegations.get(&block.header.validator) {
+            addresses.extend(delegators.keys());
+        }
+        self.stakes
+            .unbonding
+            .iter()
+            .filter(|entry| entry.release_height <= block.header.id)
+            .for_each(|entry| addresses.push(&entry.address));
+        block.txn.iter().for_each(|txn| {
+            addresses.push(&txn.txn_input.from);
+            addresses.push(&txn.txn_output.to);
+            if let Some(evidence) = &txn.evidence {
+                addresses.push(evidence.offender());
+            }
+        });
+        addresses.sort();
+        addresses.dedup();
+
+        let mut validators: Vec<&String> = block
+            .txn
+            .iter()
+            .filter_map(|txn| match (&txn.txn_type, &txn.evidence) {
+                (_, Some(evidence)) => Some(evidence.offender()),
+                (TransactionType::DELEGATE, _) | (TransactionType::UNDELEGATE, _) => {
+                    Some(&txn.txn_output.to)
+                }
+                _ => None,
+            })
+            .collect();
+        validators.sort();
+        validators.dedup();
+
+        UndoJournal {
+            balances: addresses
+                .iter()
+                .map(|a| (a.to_string(), self.accounts.balances.get(*a).copied()))
+                .collect(),
+            nonces: addresses
+                .iter()
+                .map(|a| (a.to_string(), self.accounts.nonces.get(*a).copied()))
+                .collect(),
+            stakes: addresses
+                .iter()
+                .map(|a| (a.to_string(), self.stakes.balances.get(*a).copied()))
+                .collect(),
+            delegations: validators
+                .iter()
+                .map(|v| (v.to_string(), self.stakes.delegations.get(*v).cloned()))
+                .collect(),
+            account_count: self.accounts.accounts.len(),
+            stake_count: self.stakes.accounts.len(),
+            unbonding: self.stakes.unbonding.clone(),
+            validators: self.validators.clone(),
+            slashed_count: self.slashed.len(),
+            total_supply: self.total_supply,
+            burned: self.burned,
+            height: self.height,
+        }
+    }
+
+    /// Undoes the block `journal` was recorded for. Blocks have to be
+    /// reverted newest first.
+    pub fn revert(&mut self, journal: &UndoJournal) {
+        fn restore<T: Copy>(map: &mut HashMap<String, T>, entries: &[(String, Option<T>)]) {
+            entries.iter().for_each(|(address, value)| match value {
+                Some(value) => {
+                    map.insert(address.clone(), *value);
+                }
+                None => {
+                    map.remove(address);
+                }
+            });
+        }
+
+        restore(&mut self.accounts.balances, &journal.balances);
+        restore(&mut self.accounts.nonces, &journal.nonces);
+        restore(&mut self.stakes.balances, &journal.stakes);
+        journal
+            .delegations
+            .iter()
+            .for_each(|(validator, delegators)| match delegators {
+                Some(delegators) => {
+                    self.stakes
+                        .delegations
+                        .insert(validator.clone(), delegators.clone());
+                }
+                None => {
+                    self.stakes.delegations.remove(validator);
+                }
+            });
+        self.accounts.accounts.truncate(journal.account_count);
+        self.stakes.accounts.truncate(journal.stake_count);
+        self.stakes.unbonding = journal.unbonding.clone();
+        self.validators = journal.validators.clone();
+        self.slashed.truncate(journal.slashed_count);
+        self.total_supply = journal.total_supply;
+        self.burned = journal.burned;
+        self.height = journal.height;
+    }
+
+    /// Checks `txn` against the current state without modifying it.
+    pub fn validate_txn(&self, txn: &Transaction) -> Result<(), TransactionError> {
+        self.check_txn(txn, &self.sender_state(&txn.txn_input.from))
+    }
+
+    /// What transactions from `address` are checked against, before any
+    /// pending ones.
+    pub fn sender_state(&self, address: &String) -> SenderState {
+        SenderState {
+            address: address.clone(),
+            balance: self.accounts.get_balance(address),
+            nonce: self.accounts.get_nonce(address),
+            stake: self
+                .stakes
+                .balances
+                .get(address)
+                .copied()
+                .unwrap_or_default(),
+            validator: self.validators.is_active(address),
+            delegations: BTreeMap::new(),
+        }
+    }
+
+    /// Checks `txn` on the current state after the sender's transactions
+    /// already applied to `sender`, and applies it to `sender` if it is
+    /// valid. Other accounts are read as they are in the state.
+    pub fn validate_pending(
+        &self,
+        txn: &Transaction,
+        sender: &mut SenderState,
+    ) -> Result<(), TransactionError> {
+        self.check_txn(txn, sender)?;
+        sender.apply(self, txn)
+    }
+
+    // Checks `txn` reading the sender's account from `sender`.
+    fn check_txn(&self, txn: &Transaction, sender: &SenderState) -> Result<(), TransactionError> {
+        debug_assert_eq!(txn.txn_input.from, sender.address);
+        if txn.chain_id != self.chain_id {
+            return Err(TransactionError::WrongChain(txn.chain_id.clone()));
+        } else if !matches!(Transaction::verify_txn(txn), Ok(true)) {
+            return Err(TransactionError::InvalidSignature);
+        }
+
+        let expected = sender.nonce;
+        if txn.txn_input.nonce != expected {
+            return Err(TransactionError::InvalidNonce {
+                expected,
+                actual: txn.txn_input.nonce,
+            });
+        } else if txn.txn_output.fee < self.config.min_fee {
+            return Err(TransactionError::FeeTooLow {
+                minimum: self.config.min_fee,
+                fee: txn.txn_output.fee,
+            });
+        }
+
+        let to = &txn.txn_output.to;
+        match txn.txn_type {
+            TransactionType::TRANSACTION => {
+                if !Util::is_valid_public_key(to) {
+                    return Err(TransactionError::InvalidRecipient(to.clone()));
+                }
+            }
+            TransactionType::DELEGATE => {
+                if !self.validators.is_active(to) {
+                    return Err(TransactionError::NotValidator(to.clone()));
+                }
+            }
+            TransactionType::UNDELEGATE => {
+                if !Util::is_valid_public_key(to) {
+                    return Err(TransactionError::InvalidRecipient(to.clone()));
+                }
+            }
+            TransactionType::STAKE
+            | TransactionType::VALIDATOR
+            | TransactionType::EVIDENCE
+            | TransactionType::UNSTAKE
+            | TransactionType::EXIT => {
+                if to != SYSTEM_ADDRESS {
+                    return Err(TransactionError::InvalidRecipient(to.clone()));
+                }
+            }
+        }
+
+        if let TransactionType::EVIDENCE = txn.txn_type {
+            let evidence = txn
+                .evidence
+                .as_ref()
+                .ok_or(TransactionError::InvalidEvidence(EvidenceError::Missing))?;
+            evidence
+                .verify(&self.chain_id)
+                .map_err(TransactionError::InvalidEvidence)?;
+            // Only active validators can be slashed, once per equivocation
+            // and only for what they signed since they last registered.
+            let offender = evidence.offender();
+            let registered = self.validators.registration_height(offender);
+            if !self.validators.is_active(offender) {
+                return Err(TransactionError::InvalidEvidence(
+                    EvidenceError::NotValidator(offender.clone()),
+                ));
+            } else if self
+                .slashed
+                .iter()
+                .any(|(address, height)| address == offender && *height == evidence.height())
+            {
+                return Err(TransactionError::InvalidEvidence(
+                    EvidenceError::AlreadySlashed,
+                ));
+            } else if evidence.height() <= registered {
+                return Err(TransactionError::InvalidEvidence(
+                    EvidenceError::BeforeRegistration(registered),
+                ));
+            }
+        }
+
+        let from = &txn.txn_input.from;
+        match txn.txn_type {
+            TransactionType::VALIDATOR if sender.validator => {
+                return Err(TransactionError::AlreadyValidator(from.clone()));
+            }
+            TransactionType::VALIDATOR if txn.txn_output.amount < VALIDATOR_BOND => {
+                return Err(TransactionError::BondTooLow(txn.txn_output.amount));
+            }
+            TransactionType::EXIT if !sender.validator => {
+                return Err(TransactionError::NotValidator(from.clone()));
+            }
+            TransactionType::TRANSACTION
+            | TransactionType::STAKE
+            | TransactionType::UNSTAKE
+            | TransactionType::DELEGATE
+            | TransactionType::UNDELEGATE
+                if txn.txn_output.amount == Amount::ZERO =>
+            {
+                return Err(TransactionError::ZeroAmount);
+            }
+            _ => {}
+        }
+
+        // Unstaked and undelegated funds come out of the stake and an exit
+        // moves no funds, only the fee is paid from the account.
+        let required = match txn.txn_type {
+            TransactionType::UNSTAKE | TransactionType::UNDELEGATE => {
+                let staked = match txn.txn_type {
+                    TransactionType::UNSTAKE => sender.stake,
+                    _ => sender.delegation(self, to),
+                };
+                if txn.txn_output.amount > staked {
+                    return Err(TransactionError::InsufficientStake {
+                        required: txn.txn_output.amount,
+                        available: staked,
+                    });
+                }
+                txn.txn_output.fee
+            }
+            TransactionType::EXIT => txn.txn_output.fee,
+            _ => txn
+                .txn_output
+                .amount
+                .checked_add(txn.txn_output.fee)
+                .ok_or(TransactionError::Overflow)?,
+        };
+        let available = sender.balance;
+        if required > available {
+            return Err(TransactionError::InsufficientBalance {
+                required,
+                available,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Validates `txn` and applies it, paying the fee to `validator`.
+    pub fn apply_txn(
+        &mut self,
+        txn: &Transaction,
+        validator: &String,
+    ) -> Result<(), TransactionError> {
+        self.validate_txn(txn)?;
+
+        let from = &txn.txn_input.from;
+        match txn.txn_type {
+            TransactionType::TRANSACTION => {
+                // Transfer amount
+                self.accounts
+                    .transfer(from, &txn.txn_output.to, &txn.txn_output.amount)?;
+            }
+            TransactionType::STAKE => {
+                self.accounts.decrement(from, &txn.txn_output.amount)?;
+                self.stakes.update(txn)?;
+            }
+            TransactionType::VALIDATOR => {
+                self.accounts.decrement(from, &txn.txn_output.amount)?;
+                self.validators.update(txn, self.height);
+            }
+            TransactionType::EVIDENCE => {
+                // validate_txn made sure the evidence is there.
+                // The offender is removed as if it exited, then `SLASH_PERCENT`
+                // of its stake, of everything it has unbonding, including the
+                // bond, and of the delegations to it is burned.
+                let evidence = txn.evidence.as_ref().unwrap();
+                let offender = evidence.offender();
+                self.slashed.push((offender.clone(), evidence.height()));
+                self.exit_validator(offender);
+                let burned = self.stakes.slash(offender);
+                self.total_supply = self.total_supply.checked_sub(burned).unwrap_or_default();
+                self.burned = self.burned.checked_add(burned).unwrap_or(self.burned);
+            }
+            TransactionType::UNSTAKE => {
+                let release_height = self.height + self.config.unbonding_period;
+                self.stakes
+                    .unstake(from, &txn.txn_output.amount, release_height)?;
+            }
+            TransactionType::EXIT => self.exit_validator(from),
+            TransactionType::DELEGATE => {
+                self.accounts.decrement(from, &txn.txn_output.amount)?;
+                self.stakes
+                    .delegate(&txn.txn_output.to, from, &txn.txn_output.amount)?;
+            }
+            TransactionType::UNDELEGATE => {
+                let release_height = self.height + self.config.unbonding_period;
+                self.stakes.undelegate(
+                    &txn.txn_output.to,
+                    from,
+                    &txn.txn_output.amount,
+                    release_height,
+                )?;
+            }
+        }
+        self.pay_fee(from, validator, &txn.txn_output.fee)?;
+        self.accounts.increment_nonce(from);
+        Ok(())
+    }
+
+    // Moves `fee` from `from` to `validator` and its delegators. The
+    // validator keeps its commission, the rest is split in proportion to
+    // the validator's own stake and each delegation. Shares are rounded
+    // down and the remainder goes to the validator, which also gets the
+    // whole fee when nothing is staked with it.
+    fn pay_fee(
+        &mut self,
+        from: &String,
+        validator: &String,
+        fee: &Amount,
+    ) -> Result<(), AmountError> {
+        self.accounts.initialize(validator);
+        self.accounts.decrement(from, fee)?;
+
+        let own = self
+            .stakes
+            .balances
+            .get(validator)
+            .copied()
+            .unwrap_or_default();
+        let delegators = self.stakes.delegations.get(validator);
+        let total = delegators
+            .into_iter()
+            .flat_map(|delegators| delegators.values())
+            .fold(own.units() as u128, |total, amount| {
+                total + amount.units() as u128
+            });
+        let shares: Vec<(String, Amount)> = match delegators {
+            Some(delegators) if total > 0 => {
+                let commission = self.config.commission_percent.min(100) as u128;
+                let pool = fee.units() as u128 * (100 - commission) / 100;
+                delegators
+                    .iter()
+                    .map(|(delegator, amount)| {
+                        let share = pool * amount.units() as u128 / total;
+                        (delegator.clone(), Amount::from_units(share as u64))
+                    })
+                    .collect()
+            }
+            _ => vec![],
+        };
+
+        let mut rest = *fee;
+        for (delegator, share) in shares {
+            self.accounts.increment(&delegator, &share)?;
+            rest = rest.checked_sub(share).ok_or(AmountError::Underflow)?;
+        }
+        self.accounts.increment(validator, &rest)
+    }
+
+    // Removes `address` from the validator set and queues its bond for
+    // refund after the exit delay.
+    fn exit_validator(&mut self, address: &String) {
+        let bond = self.validators.remove(address);
+        if bond != Amount::ZERO {
+            let release_height = self.height + self.config.exit_delay;
+            self.stakes.add_unbonding(address, bond, release_height);
+        }
+    }
+
+    /// Starts executing the block at `height` produced by `validator`:
+    /// unbonding entries due at this height are paid back to their owners
+    /// and the validator is credited the block reward.
+    pub fn begin_block(&mut self, height: usize, validator: &String) -> Result<(), AmountError> {
+        self.height = height;
+        self.stakes
+            .release(height)
+            .iter()
+            .try_for_each(|entry| self.accounts.increment(&entry.address, &entry.amount))?;
+
+        let reward = self.config.issuance.reward(height, self.total_supply);
+        self.accounts.increment(validator, &reward)?;
+        self.total_supply = self
+            .total_supply
+            .checked_add(reward)
+            .ok_or(AmountError::Overflow)?;
+        Ok(())
+    }
+
+    /// Executes `block`: releases due unbonding entries and applies every
+    /// transaction in order. On failure the state is partially updated and
+    /// should be discarded.
+    pub fn apply_block(&mut self, block: &Block) -> Result<(), BlockValidationError> {
+        self.begin_block(block.header.id, &block.header.validator)
+            .map_err(|_| BlockValidationError::Overflow)?;
+        block.txn.iter().enumerate().try_for_each(|(index, txn)| {
+            self.apply_txn(txn, &block.header.validator)
+                .map_err(|error| BlockValidationError::InvalidTransaction { index, error })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::UNIT;
+    use crate::slashing::Evidence;
+    use crate::wallet::Wallet;
+
+    const KEY: &str = "27a23bf39574e86464f4e638241b3ef3dd223d9a30bd97810ff29c992e747e5a230681c76f00b412ccf7757a8449c448a04acd735e497a7612b66d8bfcb8e576";
+    const OTHER: &str = "5aede624154386ca358af195e13a46981b917ee8279f30a67d7a211a3d3e7243";
+    const GOLDEN: &str = "8a88e3dd7409f195fd52db2d3cba5d72ca6709bf1d94121bf3748801b40f6f5c";
+    const OTHER_KEY: &str = "5ae5066dd048ffb8f8628c44324e63c7b8782a026009a85a96935acb4921abbc5aede624154386ca358af195e13a46981b917ee8279f30a67d7a211a3d3e7243";
+
+    #[test]
+    fn validating_leaves_the_state_untouched() {
+        let state = State::new(&Genesis::default());
+        let mut wallet = Wallet::get_wallet(String::from("01010101010101010101010101010101010101010101010101010101010101018a88e3dd7409f195fd52db2d3cba5d72ca6709bf1d94121bf3748801b40f6f5c"));
+        let txn = Transaction::new(
+            &mut wallet,
+            &state.chain_id,
+            OTHER.to_string(),
+            Amount::from_coins(1),
+            state.config.min_fee,
+            TransactionType::TRANSACTION,
+            0,
+        );
+        assert_eq!(
+            state.validate_txn(&txn),
+            Err(TransactionError::InsufficientBalance {
+                required: Amount::from_coins(2),
+                available: Amount::ZERO,
+            })
+        );
+        assert_eq!(state.accounts.accounts.len(), 2);
+        assert!(!state.accounts.balances.contains_key(&txn.txn_input.from));
+    }
+
+    #[test]
+    fn checks_pending_transactions_like_executing_them() {
+        let genesis = Genesis::default();
+        let mut state = State::new(&genesis);
+        let mut wallet = Wallet::get_wallet(KEY.to_string());
+        let from = wallet.get_public_key();
+        let mut txn = |to: &str, amount: Amount, txn_type, nonce| {
+            Transaction::new(
+                &mut wallet,
+                &genesis.chain_id,
+                to.to_string(),
+                amount,
+                genesis.config.min_fee,
+                txn_type,
+                nonce,
+            )
+        };
+        let coins = |n| Amount::from_coins(n);
+        let pending = vec![
+            txn(OTHER, coins(10), TransactionType::TRANSACTION, 0),
+            txn(SYSTEM_ADDRESS, coins(5), TransactionType::STAKE, 1),
+            txn(SYSTEM_ADDRESS, coins(1), TransactionType::UNSTAKE, 2),
+            txn(OTHER, coins(3), TransactionType::DELEGATE, 3),
+            txn(OTHER, coins(2), TransactionType::UNDELEGATE, 4),
+        ];
+
+        let mut sender = state.sender_state(&from);
+        for txn in &pending {
+            state.validate_pending(txn, &mut sender).unwrap();
+        }
+        // Spending the rest plus the fee is one coin too many.
+        let rest = txn(OTHER, sender.balance, TransactionType::TRANSACTION, 5);
+        assert!(matches!(
+            state.validate_pending(&rest, &mut sender.clone()),
+            Err(TransactionError::InsufficientBalance { .. })
+        ));
+        let undelegate = txn(OTHER, coins(2), TransactionType::UNDELEGATE, 5);
+        assert!(state
+            .validate_pending(&undelegate, &mut sender.clone())
+            .is_err());
+
+        for txn in &pending {
+            state.apply_txn(txn, &SYSTEM_ADDRESS.to_string()).unwrap();
+        }
+        let applied = state.sender_state(&from);
+        assert_eq!(sender.balance, applied.balance);
+        assert_eq!(sender.nonce, applied.nonce);
+        assert_eq!(sender.stake, applied.stake);
+        assert_eq!(sender.validator, applied.validator);
+        assert_eq!(sender.delegation(&state, &OTHER.to_string()), coins(1));
+        assert_eq!(state.stakes.delegation(&OTHER.to_string(), &from), coins(1));
+    }
+
+    #[test]
+    fn rejects_zero_amounts() {
+        let state = State::new(&Genesis::default());
+        let mut wallet = Wallet::get_wallet(KEY.to_string());
+        for (to, txn_type) in [
+            (OTHER, TransactionType::TRANSACTION),
+            (SYSTEM_ADDRESS, TransactionType::STAKE),
+            (SYSTEM_ADDRESS, TransactionType::UNSTAKE),
+            (OTHER, TransactionType::DELEGATE),
+            (OTHER, TransactionType::UNDELEGATE),
+        ] {
+            let txn = Transaction::new(
+                &mut wallet,
+                &state.chain_id,
+                to.to_string(),
+                Amount::ZERO,
+                state.config.min_fee,
+                txn_type,
+                0,
+            );
+            assert_eq!(state.validate_txn(&txn), Err(TransactionError::ZeroAmount));
+        }
+    }
+
+    #[test]
+    fn pays_the_whole_fee_to_a_validator_with_nothing_staked() {
+        let mut state = State::new(&Genesis::default());
+        let validator = OTHER.to_string();
+        let delegator = Wallet::get_wallet(KEY.to_string()).get_public_key();
+        state
+            .stakes
+            .delegate(&validator, &delegator, &Amount::ZERO)
+            .unwrap();
+        let unstake = Transaction::new(
+            &mut Wallet::get_wallet(OTHER_KEY.to_string()),
+            &state.chain_id,
+            SYSTEM_ADDRESS.to_string(),
+            Amount::from_coins(1),
+            state.config.min_fee,
+            TransactionType::UNSTAKE,
+            0,
+        );
+
+        state.apply_txn(&unstake, &validator).unwrap();
+        assert_eq!(state.stakes.staking_balance(&validator), Amount::ZERO);
+        assert_eq!(
+            state.accounts.get_balance(&validator),
+            Amount::from_coins(500)
+        );
+        assert_eq!(
+            state.accounts.get_balance(&delegator),
+            Amount::from_coins(500)
+        );
+    }
+
+    // Pays `fee` on a transfer from OTHER to the first validator, which has
+    // 2 coins staked, 3 delegated by GOLDEN and 1 by OTHER. Returns the
+    // balance changes of the validator, GOLDEN and OTHER in base units.
+    fn share_fee(commission_percent: u64, fee: Amount) -> (i128, i128, i128) {
+        let mut genesis = Genesis::default();
+        genesis.config.commission_percent = commission_percent;
+        genesis.config.min_fee = Amount::from_units(1);
+        let mut state = State::new(&genesis);
+        let validator = genesis.validators[0].clone();
+        let (golden, other) = (GOLDEN.to_string(), OTHER.to_string());
+        state
+            .stakes
+            .delegate(&validator, &golden, &Amount::from_coins(3))
+            .unwrap();
+        state
+            .stakes
+            .delegate(&validator, &other, &Amount::from_coins(1))
+            .unwrap();
+        let txn = Transaction::new(
+            &mut Wallet::get_wallet(OTHER_KEY.to_string()),
+            &genesis.chain_id,
+            validator.clone(),
+            Amount::from_coins(1),
+            fee,
+            TransactionType::TRANSACTION,
+            0,
+        );
+
+        let before = state.clone();
+        state.apply_txn(&txn, &validator).unwrap();
+        let change = |address: &String| {
+            state.accounts.get_balance(address).units() as i128
+                - before.accounts.get_balance(address).units() as i128
+        };
+        let coin = Amount::from_coins(1).units() as i128;
+        (
+            change(&validator) - coin,
+            change(&golden),
+            change(&other) + coin,
+        )
+    }
+
+    #[test]
+    fn shares_fees_in_proportion_to_stake() {
+        let fee = Amount::from_coins(1).units() as i128;
+        // 10% commission, the rest split 2:3:1.
+        assert_eq!(
+            share_fee(10, Amount::from_coins(1)),
+            (40_000_000, 45_000_000, 15_000_000 - fee)
+        );
+        // Without commission the delegators' shares round down and the
+        // remainder goes to the validator.
+        assert_eq!(
+            share_fee(0, Amount::from_coins(1)),
+            (33_333_334, 50_000_000, 16_666_666 - fee)
+        );
+        assert_eq!(share_fee(10, Amount::from_units(7)), (3, 3, 1 - 7));
+        assert_eq!(share_fee(100, Amount::from_coins(1)), (fee, 0, -fee));
+    }
+
+    // Two different blocks signed with `key` at `height`.
+    fn equivocation(genesis: &Genesis, key: &str, height: usize) -> Evidence {
+        let mut previous = Block::genesis(genesis);
+        previous.header.id = height - 1;
+        let block = |timestamp| {
+            Block::new(
+                &previous,
+                timestamp,
+                vec![],
+                String::new(),
+                genesis.difficulty,
+                Wallet::get_wallet(key.to_string()),
+            )
+        };
+        Evidence::new(&block(1), &block(2))
+    }
+
+    #[test]
+    fn slashes_an_equivocation_once() {
+        let genesis = Genesis::default();
+        let mut state = State::new(&genesis);
+        let offender = OTHER.to_string();
+        let mut reporter = Wallet::get_wallet(KEY.to_string());
+        let mut report = |evidence: &Evidence, nonce| {
+            Transaction::new_evidence(
+                &mut reporter,
+                &genesis.chain_id,
+                evidence.clone(),
+                genesis.config.min_fee,
+                nonce,
+            )
+        };
+        let evidence = equivocation(&genesis, OTHER_KEY, 1);
+
+        state.begin_block(1, &genesis.validators[0]).unwrap();
+        state
+            .apply_txn(&report(&evidence, 0), &genesis.validators[0])
+            .unwrap();
+        assert_eq!(state.slashed, vec![(offender.clone(), 1)]);
+        assert!(!state.validators.is_active(&offender));
+
+        state.begin_block(2, &genesis.validators[0]).unwrap();
+        let register = Transaction::new(
+            &mut Wallet::get_wallet(OTHER_KEY.to_string()),
+            &genesis.chain_id,
+            SYSTEM_ADDRESS.to_string(),
+            VALIDATOR_BOND,
+            genesis.config.min_fee,
+            TransactionType::VALIDATOR,
+            0,
+        );
+        state.apply_txn(&register, &genesis.validators[0]).unwrap();
+        assert_eq!(state.validators.registration_height(&offender), 2);
+        assert_eq!(
+            state.validate_txn(&report(&evidence, 1)),
+            Err(TransactionError::InvalidEvidence(
+                EvidenceError::AlreadySlashed
+            ))
+        );
+        let before = equivocation(&genesis, OTHER_KEY, 2);
+        assert_eq!(
+            state.validate_txn(&report(&before, 1)),
+            Err(TransactionError::InvalidEvidence(
+                EvidenceError::BeforeRegistration(2)
+            ))
+        );
+        let later = equivocation(&genesis, OTHER_KEY, 3);
+        assert_eq!(state.validate_txn(&report(&later, 1)), Ok(()));
+    }
+
+    #[test]
+    fn burns_the_slashed_share_of_an_equivocating_validator() {
+        let mut genesis = Genesis::default();
+        genesis.validators.truncate(1);
+        let producer = genesis.validators[0].clone();
+        let mut state = State::new(&genesis);
+        let offender = OTHER.to_string();
+        let mut key = Wallet::get_wallet(KEY.to_string());
+        let mut other = Wallet::get_wallet(OTHER_KEY.to_string());
+        let txn = |wallet: &mut Wallet, to: &str, amount, txn_type, nonce| {
+            Transaction::new(
+                wallet,
+                &genesis.chain_id,
+                to.to_string(),
+                amount,
+                genesis.config.min_fee,
+                txn_type,
+                nonce,
+            )
+        };
+
+        state.begin_block(1, &producer).unwrap();
+        for txn in [
+            txn(
+                &mut other,
+                SYSTEM_ADDRESS,
+                VALIDATOR_BOND,
+                TransactionType::VALIDATOR,
+                0,
+            ),
+            txn(
+                &mut key,
+                OTHER,
+                Amount::from_coins(4),
+                TransactionType::DELEGATE,
+                0,
+            ),
+        ] {
+            state.apply_txn(&txn, &producer).unwrap();
+        }
+        let evidence = equivocation(&genesis, OTHER_KEY, 2);
+        let report = Transaction::new_evidence(
+            &mut key,
+            &genesis.chain_id,
+            evidence,
+            genesis.config.min_fee,
+            1,
+        );
+        state.begin_block(2, &producer).unwrap();
+        let before = state.clone();
+        state.apply_txn(&report, &producer).unwrap();
+
+        // Half of the stake of 1, the bond of 25 and the delegation of 4.
+        let burned = Amount::from_units(15 * UNIT);
+        assert!(!state.validators.is_active(&offender));
+        assert_eq!(
+            state.stakes.balances[&offender],
+            Amount::from_units(UNIT / 2)
+        );
+        assert_eq!(
+            state.stakes.delegation(&offender, &report.txn_input.from),
+            Amount::from_coins(2)
+        );
+        let bond = state.stakes.unbonding.last().unwrap();
+        assert_eq!(
+            (bond.address.as_str(), bond.amount, bond.release_height),
+            (
+                OTHER,
+                Amount::from_units(25 * UNIT / 2),
+                2 + genesis.config.exit_delay
+            )
+        );
+        assert_eq!(state.burned, burned);
+        assert_eq!(
+            state.total_supply,
+            before.total_supply.checked_sub(burned).unwrap()
+        );
+        assert_eq!(state.supply().check(), Ok(()));
+    }
+
+    #[test]
+    fn reverts_a_block_with_its_journal() {
+        let genesis = Genesis::default();
+        let mut wallet = Wallet::get_wallet(KEY.to_string());
+        let mut txn = |to: &str, amount: &str, txn_type, nonce| {
+            Transaction::new(
+                &mut wallet,
+                &genesis.chain_id,
+                to.to_string(),
+                amount.parse().unwrap(),
+                genesis.config.min_fee,
+                txn_type,
+                nonce,
+            )
+        };
+        let txns = vec![
+            txn(OTHER, "10", TransactionType::TRANSACTION, 0),
+            txn(SYSTEM_ADDRESS, "5", TransactionType::STAKE, 1),
+            txn(SYSTEM_ADDRESS, "1", TransactionType::UNSTAKE, 2),
+            txn(OTHER, "3", TransactionType::DELEGATE, 3),
+        ];
+        let block = Block::new(
+            &Block::genesis(&genesis),
+            genesis.timestamp + 1,
+            txns,
+            String::new(),
+            genesis.difficulty,
+            Wallet::get_wallet(KEY.to_string()),
+        );
+
+        let before = State::new(&genesis);
+        let mut state = before.clone();
+        let journal = state.journal(&block);
+        state.apply_block(&block).unwrap();
+        assert_ne!(state.root(), before.root());
+        assert_eq!(state.supply().check(), Ok(()));
+
+        state.revert(&journal);
+        assert_eq!(state.root(), before.root());
+        assert_eq!(state.accounts.accounts, before.accounts.accounts);
+        assert_eq!(state.total_supply, before.total_supply);
+        assert_eq!(state.height, before.height);
+    }
+
+    #[test]
+    fn releases_unstaked_funds_after_the_unbonding_period() {
+        let genesis = Genesis::default();
+        let mut state = State::new(&genesis);
+        let validator = genesis.validators[0].clone();
+        let unstake = Transaction::new(
+            &mut Wallet::get_wallet(KEY.to_string()),
+            &genesis.chain_id,
+            SYSTEM_ADDRESS.to_string(),
+            Amount::from_coins(2),
+            genesis.config.min_fee,
+            TransactionType::UNSTAKE,
+            0,
+        );
+        state.begin_block(1, &validator).unwrap();
+        state.apply_txn(&unstake, &validator).unwrap();
+        let balance = state.accounts.get_balance(&validator);
+        let release_height = 1 + genesis.config.unbonding_period;
+        assert_eq!(
+            state.stakes.unbonding,
+            vec![Unbonding {
+                address: validator.clone(),
+                amount: Amount::from_coins(2),
+                release_height,
+            }]
+        );
+
+        // Blocks produced by someone else, so only the release moves funds.
+        state
+            .begin_block(release_height - 1, &OTHER.to_string())
+            .unwrap();
+        assert_eq!(state.accounts.get_balance(&validator), balance);
+        state
+            .begin_block(release_height, &OTHER.to_string())
+            .unwrap();
+        assert_eq!(
+            state.accounts.get_balance(&validator),
+            balance.checked_add(Amount::from_coins(2)).unwrap()
+        );
+        assert!(state.stakes.unbonding.is_empty());
+        assert_eq!(state.supply().check(), Ok(()));
+    }
+
+    #[test]
+    fn refunds_the_bond_after_the_exit_delay() {
+        let mut genesis = Genesis::default();
+        genesis.validators.truncate(1);
+        let producer = genesis.validators[0].clone();
+        let offender = OTHER.to_string();
+        let mut state = State::new(&genesis);
+        let mut wallet = Wallet::get_wallet(OTHER_KEY.to_string());
+        let mut txn = |amount, txn_type, nonce| {
+            Transaction::new(
+                &mut wallet,
+                &genesis.chain_id,
+                SYSTEM_ADDRESS.to_string(),
+                amount,
+                genesis.config.min_fee,
+                txn_type,
+                nonce,
+            )
+        };
+
+        state.begin_block(1, &producer).unwrap();
+        state
+            .apply_txn(
+                &txn(VALIDATOR_BOND, TransactionType::VALIDATOR, 0),
+                &producer,
+            )
+            .unwrap();
+        assert_eq!(
+            state.validate_txn(&txn(VALIDATOR_BOND, TransactionType::VALIDATOR, 1)),
+            Err(TransactionError::AlreadyValidator(offender.clone()))
+        );
+
+        state.begin_block(2, &producer).unwrap();
+        state
+            .apply_txn(&txn(Amount::ZERO, TransactionType::EXIT, 1), &producer)
+            .unwrap();
+        assert!(!state.validators.is_active(&offender));
+        assert_eq!(
+            state.validate_txn(&txn(Amount::ZERO, TransactionType::EXIT, 2)),
+            Err(TransactionError::NotValidator(offender.clone()))
+        );
+        let balance = state.accounts.get_balance(&offender);
+
+        let refund_height = 2 + genesis.config.exit_delay;
+        state.begin_block(refund_height - 1, &producer).unwrap();
+        assert_eq!(state.accounts.get_balance(&offender), balance);
+        state.begin_block(refund_height, &producer).unwrap();
+        assert_eq!(
+            state.accounts.get_balance(&offender),
+            balance.checked_add(VALIDATOR_BOND).unwrap()
+        );
+        assert_eq!(state.supply().check(), Ok(()));
+    }
+}
@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use log::warn;
+
+use crate::block::{Block, BlockValidationError};
+
+const BLOCK_LOG_FILE: &str = "blocks.log";
+const RECORD_HEADER_LEN: u64 = 4;
+
+#[derive(Debug)]
+pub enum StoreError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    UnknownHeight(usize),
+    InvalidChain(BlockValidationError),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StoreError::Io(err) => write!(f, "io error: {}", err),
+            StoreError::Json(err) => write!(f, "malformed block record: {}", err),
+            StoreError::UnknownHeight(height) => write!(f, "no block at height {}", height),
+            StoreError::InvalidChain(err) => write!(f, "stored chain is invalid: {}", err),
+        }
+    }
+}
+
+impl From<std::io::Error> for StoreError {
+    fn from(err: std::io::Error) -> Self {
+        StoreError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for StoreError {
+    fn from(err: serde_json::Error) -> Self {
+        StoreError::Json(err)
+    }
+}
+
+/// Storage backend for the canonical chain. Blocks are addressed by height
+/// (`Block::id`) and by hash; account, stake and validator state is rebuilt
+/// by replaying the stored blocks.
+pub trait ChainStore: Send {
+    fn append(&mut self, block: &Block) -> Result<(), StoreError>;
+    /// Drops every block at `height` and above.
+    fn truncate(&mut self, height: usize) -> Result<(), StoreError>;
+    fn get_by_height(&self, height: usize) -> Result<Option<Block>, StoreError>;
+    fn get_by_hash(&self, hash: &str) -> Result<Option<Block>, StoreError>;
+    fn height(&self) -> usize;
+
+    fn load_chain(&self) -> Result<Vec<Block>, StoreError> {
+        (0..self.height())
+            .map(|height| {
+                self.get_by_height(height)?
+                    .ok_or(StoreError::UnknownHeight(height))
+            })
+            .collect()
+    }
+}
+
+pub struct MemoryStore {
+    blocks: Vec<Block>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self { blocks: vec![] }
+    }
+}
+
+impl ChainStore for MemoryStore {
+    fn append(&mut self, block: &Block) -> Result<(), StoreError> {
+        self.blocks.push(block.clone());
+        Ok(())
+    }
+
+    fn truncate(&mut self, height: usize) -> Result<(), StoreError> {
+        self.blocks.truncate(height);
+        Ok(())
+    }
+
+    fn get_by_height(&self, height: usize) -> Result<Option<Block>, StoreError> {
+        Ok(self.blocks.get(height).cloned())
+    }
+
+    fn get_by_hash(&self, hash: &str) -> Result<Option<Block>, StoreError> {
+        Ok(self.blocks.iter().find(|block| block.hash == hash).cloned())
+    }
+
+    fn height(&self) -> usize {
+        self.blocks.len()
+    }
+}
+
+/// Append-only block log. Each record is a 4 byte big-endian length followed
+/// by the JSON encoded block. The height and hash indexes are rebuilt from
+/// the log on open; a torn record left by a crash at the end is cut off, a
+/// complete record that can't be read fails the open.
+pub struct DiskStore {
+    file: File,
+    offsets: Vec<u64>,
+    hashes: HashMap<String, usize>,
+    end: u64,
+}
+
+impl DiskStore {
+    pub fn open(dir: &Path) -> Result<Self, StoreError> {
+        fs::create_dir_all(dir)?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.join(BLOCK_LOG_FILE))?;
+
+        let mut store = Self {
+            file,
+            offsets: vec![],
+            hashes: HashMap::new(),
+            end: 0,
+        };
+        store.build_index()?;
+        Ok(store)
+    }
+
+    fn build_index(&mut self) -> Result<(), StoreError> {
+        let len = self.file.metadata()?.len();
+        let mut offset = 0;
+
+        while offset + RECORD_HEADER_LEN <= len {
+            if offset + self.record_len(offset)? > len {
+                break;
+            }
+            let (block, record_len) = self.read_record(offset)?;
+            self.offsets.push(offset);
+            self.hashes.insert(block.hash, self.offsets.len() - 1);
+            offset += record_len;
+        }
+
+        if offset != len {
+            warn!(
+                "block log has {} trailing bytes after {} blocks, truncating",
+                len - offset,
+                self.offsets.len()
+            );
+            self.file.set_len(offset)?;
+        }
+        self.end = offset;
+        Ok(())
+    }
+
+    // Length of the record at `offset` including its header.
+    fn record_len(&self, offset: u64) -> Result<u64, StoreError> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset))?;
+
+        let mut len_bytes = [0u8; RECORD_HEADER_LEN as usize];
+        file.read_exact(&mut len_bytes)?;
+        Ok(RECORD_HEADER_LEN + u32::from_be_bytes(len_bytes) as u64)
+    }
+
+    fn read_record(&self, offset: u64) -> Result<(Block, u64), StoreError> {
+        let record_len = self.record_len(offset)?;
+        let mut data = vec![0u8; (record_len - RECORD_HEADER_LEN) as usize];
+        let mut file = &self.file;
+        file.read_exact(&mut data)?;
+        let block = serde_json::from_slice::<Block>(&data)?;
+
+        Ok((block, record_len))
+    }
+}
+
+impl ChainStore for DiskStore {
+    fn append(&mut self, block: &Block) -> Result<(), StoreError> {
+        let data = serde_json::to_vec(block)?;
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN as usize + data.len());
+        record.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        record.extend_from_slice(&data);
+
+        self.file.seek(SeekFrom::Start(self.end))?;
+        self.file.write_all(&record)?;
+        self.file.sync_data()?;
+
+        self.offsets.push(self.end);
+        self.hashes
+            .insert(block.hash.clone(), self.offsets.len() - 1);
+        self.end += record.len() as u64;
+        Ok(())
+    }
+
+    fn truncate(&mut self, height: usize) -> Result<(), StoreError> {
+        if height >= self.offsets.len() {
+            return Ok(());
+        }
+
+        let end = self.offsets[height];
+        self.file.set_len(end)?;
+        self.file.sync_data()?;
+
+        self.offsets.truncate(height);
+        self.hashes.retain(|_, h| *h < height);
+        self.end = end;
+        Ok(())
+    }
+
+    fn get_by_height(&self, height: usize) -> Result<Option<Block>, StoreError> {
+        match self.offsets.get(height) {
+            Some(offset) => Ok(Some(self.read_record(*offset)?.0)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_by_hash(&self, hash: &str) -> Result<Option<Block>, StoreError> {
+        match self.hashes.get(hash) {
+            Some(height) => self.get_by_height(*height),
+            None => Ok(None),
+        }
+    }
+
+    fn height(&self) -> usize {
+        self.offsets.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::genesis::Genesis;
+    use std::path::PathBuf;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("pos-store-{}-{}", name, crate::util::Util::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn log_len(dir: &Path) -> u64 {
+        fs::metadata(dir.join(BLOCK_LOG_FILE)).unwrap().len()
+    }
+
+    #[test]
+    fn cuts_off_a_torn_record() {
+        let dir = temp_dir("torn");
+        let genesis = Block::genesis(&Genesis::default());
+        let mut store = DiskStore::open(&dir).unwrap();
+        store.append(&genesis).unwrap();
+        let len = log_len(&dir);
+
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.join(BLOCK_LOG_FILE))
+            .unwrap();
+        file.write_all(&100u32.to_be_bytes()).unwrap();
+        file.write_all(b"{\"header\"").unwrap();
+
+        let store = DiskStore::open(&dir).unwrap();
+        assert_eq!(store.height(), 1);
+        assert_eq!(store.get_by_hash(&genesis.hash).unwrap(), Some(genesis));
+        assert_eq!(log_len(&dir), len);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_to_open_an_unreadable_record() {
+        let dir = temp_dir("malformed");
+        let mut store = DiskStore::open(&dir).unwrap();
+        store.append(&Block::genesis(&Genesis::default())).unwrap();
+
+        let data = b"{\"not\":\"a block\"}";
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.join(BLOCK_LOG_FILE))
+            .unwrap();
+        file.write_all(&(data.len() as u32).to_be_bytes()).unwrap();
+        file.write_all(data).unwrap();
+        let len = log_len(&dir);
+
+        assert!(matches!(DiskStore::open(&dir), Err(StoreError::Json(_))));
+        assert_eq!(log_len(&dir), len);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn memory_store_truncates() {
+        let genesis = Block::genesis(&Genesis::default());
+        let mut store = MemoryStore::new();
+        store.append(&genesis).unwrap();
+        assert_eq!(store.load_chain().unwrap(), vec![genesis.clone()]);
+        store.truncate(0).unwrap();
+        assert_eq!(store.height(), 0);
+        assert_eq!(store.get_by_hash(&genesis.hash).unwrap(), None);
+    }
+}
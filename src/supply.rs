@@ -0,0 +1,68 @@
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::amount::Amount;
+
+/// Where the coins in existence are. `total` and `burned` are tracked as
+/// coins are issued and burned, the rest is summed up from the state.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Supply {
+    pub total: Amount,
+    pub circulating: Amount,
+    pub staked: Amount,
+    pub delegated: Amount,
+    pub bonded: Amount,
+    pub unbonding: Amount,
+    pub burned: Amount,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SupplyError {
+    Mismatch { total: Amount, held: Amount },
+    Overflow,
+}
+
+impl fmt::Display for SupplyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SupplyError::Mismatch { total, held } => {
+                write!(f, "total supply is {} but {} is held", total, held)
+            }
+            SupplyError::Overflow => write!(f, "held funds overflow"),
+        }
+    }
+}
+
+impl Supply {
+    /// Sum of the funds held in accounts, stakes, delegations, validator
+    /// bonds and the unbonding queue.
+    pub fn held(&self) -> Result<Amount, SupplyError> {
+        sum([
+            &self.circulating,
+            &self.staked,
+            &self.delegated,
+            &self.bonded,
+            &self.unbonding,
+        ]
+        .into_iter())
+        .ok_or(SupplyError::Overflow)
+    }
+
+    /// Checks that every coin in existence is held somewhere: funds are
+    /// only created by block rewards and only destroyed by burning.
+    pub fn check(&self) -> Result<(), SupplyError> {
+        let held = self.held()?;
+        if held != self.total {
+            return Err(SupplyError::Mismatch {
+                total: self.total,
+                held,
+            });
+        }
+        Ok(())
+    }
+}
+
+pub fn sum<'a>(mut amounts: impl Iterator<Item = &'a Amount>) -> Option<Amount> {
+    amounts.try_fold(Amount::ZERO, |total, amount| total.checked_add(*amount))
+}
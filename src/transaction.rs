@@ -1,30 +1,40 @@
+use crate::amount::Amount;
+use crate::encoding::Encoder;
+use crate::slashing::Evidence;
 use crate::util::{Util, VerifySigErr};
 use crate::wallet::Wallet;
 use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-pub const TRANSACTION_FEE: f64 = 1.0;
-
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum TransactionType {
     TRANSACTION,
     STAKE,
     VALIDATOR,
+    EVIDENCE,
+    UNSTAKE,
+    EXIT,
+    DELEGATE,
+    UNDELEGATE,
 }
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TransactionInput {
     pub timestamp: i64,
     pub from: String,
+    pub nonce: u64,
     pub signature: String,
 }
 
 impl TransactionInput {
-    pub fn new(sender_wallet: &mut Wallet, txn_output: &String) -> Self {
+    // The signature is filled in by `Transaction::new` once the whole
+    // transaction body is known.
+    pub fn new(sender_wallet: &mut Wallet, nonce: u64) -> Self {
         Self {
             timestamp: Utc::now().timestamp(),
             from: sender_wallet.get_public_key(),
-            signature: sender_wallet.sign(txn_output),
+            nonce,
+            signature: String::new(),
         }
     }
 }
@@ -32,12 +42,12 @@ impl TransactionInput {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TransactionOutput {
     pub to: String,
-    pub amount: f64,
-    pub fee: f64,
+    pub amount: Amount,
+    pub fee: Amount,
 }
 
 impl TransactionOutput {
-    pub fn new(to: String, amount: f64, fee: f64) -> Self {
+    pub fn new(to: String, amount: Amount, fee: Amount) -> Self {
         Self {
             to: to,
             amount: amount,
@@ -49,9 +59,14 @@ impl TransactionOutput {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Transaction {
     pub id: Uuid,
+    // Network the transaction is for, see `Genesis::chain_id`.
+    pub chain_id: String,
     pub txn_type: TransactionType,
     pub txn_input: TransactionInput,
     pub txn_output: TransactionOutput,
+    // Only set on EVIDENCE transactions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub evidence: Option<Evidence>,
 }
 
 impl PartialEq for Transaction {
@@ -61,50 +76,184 @@ impl PartialEq for Transaction {
 }
 
 pub enum VerifyTxnError {
-    DecodeJsonErr(serde_json::Error),
     VerifySigErr(VerifySigErr),
 }
 
+impl TransactionType {
+    fn tag(&self) -> u8 {
+        match self {
+            TransactionType::TRANSACTION => 0,
+            TransactionType::STAKE => 1,
+            TransactionType::VALIDATOR => 2,
+            TransactionType::EVIDENCE => 3,
+            TransactionType::UNSTAKE => 4,
+            TransactionType::EXIT => 5,
+            TransactionType::DELEGATE => 6,
+            TransactionType::UNDELEGATE => 7,
+        }
+    }
+}
+
 impl Transaction {
     pub fn new(
         sender_wallet: &mut Wallet,
+        chain_id: &str,
         to: String,
-        amount: f64,
+        amount: Amount,
+        fee: Amount,
         txn_type: TransactionType,
-    ) -> Result<Self, serde_json::Error> {
-        let txn_output = TransactionOutput::new(to, amount, TRANSACTION_FEE);
-        let serialized = match serde_json::to_string(&txn_output) {
-            Ok(serialized) => serialized,
-            Err(e) => return Err(e),
+        nonce: u64,
+    ) -> Self {
+        let txn = Self {
+            id: Util::id(),
+            chain_id: chain_id.to_string(),
+            txn_type,
+            txn_input: TransactionInput::new(sender_wallet, nonce),
+            txn_output: TransactionOutput::new(to, amount, fee),
+            evidence: None,
         };
-        let txn_input = TransactionInput::new(sender_wallet, &serialized);
+        txn.sign(sender_wallet)
+    }
 
-        Ok(Self {
+    /// Reports an equivocating validator. The reporter pays the fee, nothing
+    /// is transferred.
+    pub fn new_evidence(
+        sender_wallet: &mut Wallet,
+        chain_id: &str,
+        evidence: Evidence,
+        fee: Amount,
+        nonce: u64,
+    ) -> Self {
+        let txn = Self {
             id: Util::id(),
-            txn_type: txn_type,
-            txn_output: txn_output,
-            txn_input: txn_input,
-        })
+            chain_id: chain_id.to_string(),
+            txn_type: TransactionType::EVIDENCE,
+            txn_input: TransactionInput::new(sender_wallet, nonce),
+            txn_output: TransactionOutput::new(String::from("0"), Amount::ZERO, fee),
+            evidence: Some(evidence),
+        };
+        txn.sign(sender_wallet)
     }
 
-    pub fn verify_txn(txn: &Transaction) -> Result<bool, VerifyTxnError> {
-        let txn_message = match serde_json::to_string(&txn.txn_output) {
-            Ok(txn_message) => txn_message,
-            Err(e) => return Err(VerifyTxnError::DecodeJsonErr(e)),
-        };
+    fn sign(mut self, sender_wallet: &mut Wallet) -> Self {
+        self.txn_input.signature = sender_wallet.sign(&self.signing_hash());
+        self
+    }
+
+    /// Canonical encoding of every field except the signature. The chain id
+    /// is signed so a transaction is only valid on the network it was
+    /// created for. Evidence is appended as the hash and signature of both
+    /// headers.
+    pub fn body_bytes(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new("pos/txn/v1");
+        encoder
+            .str(&self.chain_id)
+            .bytes(self.id.as_bytes())
+            .u8(self.txn_type.tag())
+            .str(&self.txn_input.from)
+            .str(&self.txn_output.to)
+            .u64(self.txn_output.amount.units())
+            .u64(self.txn_output.fee.units())
+            .u64(self.txn_input.nonce)
+            .i64(self.txn_input.timestamp);
+        if let Some(evidence) = &self.evidence {
+            encoder
+                .str(&evidence.first.hash())
+                .str(&evidence.first.signature)
+                .str(&evidence.second.hash())
+                .str(&evidence.second.signature);
+        }
+        encoder.finish()
+    }
+
+    /// Hash of the body, this is what the sender signs.
+    pub fn signing_hash(&self) -> String {
+        Util::hash_bytes(&self.body_bytes())
+    }
+
+    /// Canonical encoding of the body followed by the signature.
+    pub fn signed_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.body_bytes();
+        bytes.extend(
+            Encoder::new("pos/txn-sig/v1")
+                .str(&self.txn_input.signature)
+                .finish(),
+        );
+        bytes
+    }
+
+    /// Hash of the body together with its signature, used to commit to the
+    /// transaction in a block.
+    pub fn hash(&self) -> String {
+        Util::hash_bytes(&self.signed_bytes())
+    }
+
+    /// Size of the signed canonical encoding in bytes, which fee rates and
+    /// block size limits are measured in.
+    pub fn size(&self) -> usize {
+        self.signed_bytes().len()
+    }
 
-        let result = match Util::verify_signature(
+    pub fn verify_txn(txn: &Transaction) -> Result<bool, VerifyTxnError> {
+        Util::verify_signature(
             &txn.txn_input.from,
-            &txn_message,
+            &txn.signing_hash(),
             &txn.txn_input.signature,
-        ) {
-            Ok(result) => result,
-            Err(e) => match e {
-                VerifySigErr::DecodeStrError(_) => false,
-                VerifySigErr::DecodeHexError(_) => false,
-            },
-        };
+        )
+        .map_err(VerifyTxnError::VerifySigErr)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// The golden vector transaction from the README.
+    pub fn golden_txn() -> Transaction {
+        let mut wallet = Wallet::get_wallet(String::from("01010101010101010101010101010101010101010101010101010101010101018a88e3dd7409f195fd52db2d3cba5d72ca6709bf1d94121bf3748801b40f6f5c"));
+        let mut txn = Transaction::new(
+            &mut wallet,
+            "pos-devnet",
+            String::from("5aede624154386ca358af195e13a46981b917ee8279f30a67d7a211a3d3e7243"),
+            "12.5".parse().unwrap(),
+            Amount::from_coins(1),
+            TransactionType::TRANSACTION,
+            0,
+        );
+        txn.id = Uuid::parse_str("6f9619ff-8b86-d011-b42d-00c04fc964ff").unwrap();
+        txn.txn_input.timestamp = 1650205976;
+        txn.sign(&mut wallet)
+    }
+
+    #[test]
+    fn matches_golden_vectors() {
+        let txn = golden_txn();
+        assert_eq!(
+            hex::encode(txn.body_bytes()),
+            "0000000a706f732f74786e2f76310000000a706f732d6465766e6574000000106f9619ff8b86d011b42d00c04fc964ff0000000040386138386533646437343039663139356664353264623264336362613564373263613637303962663164393431323162663337343838303162343066366635630000004035616564653632343135343338366361333538616631393565313361343639383162393137656538323739663330613637643761323131613364336537323433000000004a817c800000000005f5e100000000000000000000000000625c2518"
+        );
+        assert_eq!(
+            txn.signing_hash(),
+            "e5e3bc6b0732c9c70d96199557ab2c6d140c1bdeb230c7d87cbfea5d9c5db8c8"
+        );
+        assert_eq!(
+            txn.txn_input.signature,
+            "63fba0675ca6cf0021904f32c1ba64d318bf809a3ffaf76b9526798ef23f0df3f5710c2315b41d780cb2066aad6785e75d8d742014b618ab6603553df19a4707"
+        );
+        assert_eq!(
+            txn.hash(),
+            "093c32965f1611250ab79302d6c6b103b43ec79d7cd4efdf1b3f82c1fd5da9b8"
+        );
+        assert!(matches!(Transaction::verify_txn(&txn), Ok(true)));
+        // The `pos/txn-sig/v1` tag and the hex signature, each after a 4 byte
+        // length.
+        assert_eq!(txn.size(), txn.body_bytes().len() + 4 + 14 + 4 + 128);
+    }
 
-        Ok(result)
+    #[test]
+    fn signature_covers_the_body() {
+        let mut txn = golden_txn();
+        txn.txn_output.fee = Amount::from_coins(2);
+        assert!(!matches!(Transaction::verify_txn(&txn), Ok(true)));
     }
 }
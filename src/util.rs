@@ -1,7 +1,7 @@
 use ed25519_dalek::{ed25519::Error, PublicKey, Signature, Verifier};
 use hex::FromHexError;
 use log::warn;
-use sha256::digest_bytes;
+use sha2::{Digest, Sha256};
 use uuid::Uuid;
 
 pub struct Util;
@@ -44,7 +44,14 @@ impl Util {
             .is_ok())
     }
 
-    pub fn hash(data: &String) -> String {
-        digest_bytes(data.as_bytes())
+    pub fn is_valid_public_key(public_key: &String) -> bool {
+        match hex::decode(public_key) {
+            Ok(bytes) => PublicKey::from_bytes(&bytes).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    pub fn hash_bytes(data: &[u8]) -> String {
+        hex::encode(Sha256::digest(data))
     }
 }
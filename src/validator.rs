@@ -1,22 +1,55 @@
+use std::collections::HashMap;
+
+use crate::amount::Amount;
 use crate::transaction::Transaction;
 
+pub const VALIDATOR_BOND: Amount = Amount::from_coins(25);
+
+#[derive(Debug, Clone)]
 pub struct Validator {
     pub accounts: Vec<String>,
+    /// Bond paid by each registered validator, refunded after it exits.
+    pub bonds: HashMap<String, Amount>,
+    /// Height of the block that registered each validator, genesis
+    /// validators have none.
+    pub registered: HashMap<String, usize>,
 }
 
 impl Validator {
     pub fn new() -> Self {
         Self {
-            accounts: vec![
-                "230681c76f00b412ccf7757a8449c448a04acd735e497a7612b66d8bfcb8e576".to_string(),
-                "5aede624154386ca358af195e13a46981b917ee8279f30a67d7a211a3d3e7243".to_string()
-            ],
+            accounts: vec![],
+            bonds: HashMap::new(),
+            registered: HashMap::new(),
         }
     }
 
-    pub fn update(&mut self, txn: &Transaction) -> bool {
-        if txn.txn_output.amount >= 25.0 && txn.txn_output.to == "0".to_string() {
+    pub fn is_active(&self, address: &String) -> bool {
+        self.accounts.contains(address)
+    }
+
+    pub fn registration_height(&self, address: &String) -> usize {
+        self.registered.get(address).copied().unwrap_or_default()
+    }
+
+    /// Removes `address` from the validator set and returns its bond.
+    pub fn remove(&mut self, address: &String) -> Amount {
+        self.accounts.retain(|account| account != address);
+        self.registered.remove(address);
+        self.bonds.remove(address).unwrap_or_default()
+    }
+
+    /// Registers the sender of `txn` in the block at `height`.
+    pub fn update(&mut self, txn: &Transaction, height: usize) -> bool {
+        if txn.txn_output.amount >= VALIDATOR_BOND
+            && txn.txn_output.to == "0".to_string()
+            && !self.is_active(&txn.txn_input.from)
+        {
             self.accounts.push(txn.txn_input.from.to_string());
+            self.bonds
+                .insert(txn.txn_input.from.to_string(), txn.txn_output.amount);
+            self.registered
+                .insert(txn.txn_input.from.to_string(), height);
             return true;
         }
         false
@@ -2,6 +2,7 @@ use ed25519_dalek::{Keypair, Signer};
 use rand::rngs::OsRng;
 use serde::{Deserialize, Serialize};
 
+use crate::amount::Amount;
 use crate::blockchain::Blockchain;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -45,7 +46,7 @@ impl Wallet {
         hex::encode(Wallet::get_keypair(&self.key_pair).public.as_bytes())
     }
 
-    pub fn get_balance<'a>(&mut self, blockchain: &'a mut Blockchain) -> &'a f64 {
+    pub fn get_balance(&mut self, blockchain: &Blockchain) -> Amount {
         blockchain.get_balance(&self.get_public_key())
     }
 }